@@ -0,0 +1,851 @@
+// Audio Processing Unit: two square wave channels (the first with a frequency sweep),
+// a wave channel playing back samples from wave RAM and a noise channel, mixed down to stereo.
+
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+const CPU_CLOCK: u32 = 4194304;
+// The frame sequencer clocks length counters, envelopes and the sweep at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK / 512;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],   // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1],   // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1],   // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0],   // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits of NR10-NR52 that always read back as 1 (unused or write-only bits)
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,   // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,   // (unused), NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,   // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,   // (unused), NR41-NR44
+    0x00, 0x00, 0x70,               // NR50-NR52
+];
+
+
+#[derive(Clone)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        LengthCounter { enabled: false, counter: 0, max }
+    }
+
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - (value as u16 & (self.max - 1));
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 { self.counter = self.max; }
+    }
+
+    fn clock(&mut self) -> bool {
+        // Returns true when the counter runs out, which disables the channel
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+}
+
+
+#[derive(Clone)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Envelope { initial_volume: 0, increase: false, period: 0, volume: 0, timer: 0 }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 { return; }
+
+        if self.timer > 0 { self.timer -= 1; }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+
+#[derive(Clone)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep { period: 0, negate: false, shift: 0, timer: 0, enabled: false, shadow_frequency: 0 }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0x07;
+        self.negate = value & 0x08 != 0;
+        self.shift = value & 0x07;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate { self.shadow_frequency - delta } else { self.shadow_frequency + delta }
+    }
+}
+
+
+#[derive(Clone)]
+struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: i32,
+    length: LengthCounter,
+    envelope: Envelope,
+    // Only channel 1 has a frequency sweep unit
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    fn new(with_sweep: bool) -> Self {
+        SquareChannel {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        // reg is the register number within the channel: NRx0 to NRx4
+        match reg {
+            0 => if let Some(sweep) = &mut self.sweep { sweep.write(value) },
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value);
+            }
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = value & 0xF8 != 0;
+                if !self.dac_enabled { self.enabled = false; }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 { self.trigger(); }
+            }
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            // The overflow check is done immediately if the shift is non-zero
+            if sweep.shift != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+
+        if sweep.timer > 0 { sweep.timer -= 1; }
+        if sweep.timer != 0 { return; }
+        sweep.reload_timer();
+
+        if sweep.enabled && sweep.period != 0 {
+            let new_frequency = sweep.next_frequency();
+            if new_frequency > 2047 {
+                self.enabled = false;
+            } else if sweep.shift != 0 {
+                sweep.shadow_frequency = new_frequency;
+                self.frequency = new_frequency;
+                // The new frequency is run through the overflow check again, but not applied
+                if sweep.next_frequency() > 2047 { self.enabled = false; }
+            }
+        }
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        self.timer -= t_cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled { return 0; }
+        DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+    }
+}
+
+
+#[derive(Clone)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    sample: u8,
+    length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled { self.enabled = false; }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 { self.trigger(); }
+            }
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        self.timer -= t_cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 0x1F;
+            // Each byte of wave RAM holds two 4-bit samples, upper nibble first
+            let byte = self.wave_ram[(self.position / 2) as usize];
+            self.sample = if self.position & 0x01 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled { return 0; }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample,
+            2 => self.sample >> 1,
+            _ => self.sample >> 2,
+        }
+    }
+}
+
+
+#[derive(Clone)]
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    width_7bit: bool,
+    divisor_code: u8,
+    timer: i32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            dac_enabled: false,
+            clock_shift: 0,
+            width_7bit: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift) as i32
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        match reg {
+            1 => self.length.load(value),
+            2 => {
+                self.envelope.write(value);
+                self.dac_enabled = value & 0xF8 != 0;
+                if !self.dac_enabled { self.enabled = false; }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_7bit = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 { self.trigger(); }
+            }
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step(&mut self, t_cycles: u32) {
+        self.timer -= t_cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            // Shift the LFSR right, feeding back the XOR of the two low bits into bit 14 (and bit 6 in 7-bit mode)
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.width_7bit {
+                self.lfsr = (self.lfsr & !0x0040) | (feedback << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled { return 0; }
+        // The output is the inverted low bit of the LFSR
+        if self.lfsr & 0x01 == 0 { self.envelope.volume } else { 0 }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct Apu {
+    powered: bool,
+    // Last values written to NR10-NR52, for reading back
+    regs: [u8; 0x17],
+
+    ch1: SquareChannel,
+    ch2: SquareChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+
+    frame_sequencer_timer: u32,
+    frame_sequencer_step: u8,
+
+    sample_rate: u32,
+    sample_timer: u32,
+    // Interleaved stereo samples (left, right), waiting to be taken by the frontend
+    samples: Vec<f32>,
+    // State of the high-pass filter that models the capacitors on the audio output
+    capacitor: (f32, f32),
+    charge_factor: f32,
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Apu {
+        let mut apu = Apu {
+            powered: false,
+            regs: [0; 0x17],
+
+            ch1: SquareChannel::new(true),
+            ch2: SquareChannel::new(false),
+            ch3: WaveChannel::new(),
+            ch4: NoiseChannel::new(),
+
+            frame_sequencer_timer: FRAME_SEQUENCER_PERIOD,
+            frame_sequencer_step: 0,
+
+            sample_rate,
+            sample_timer: 0,
+            samples: Vec::new(),
+            capacitor: (0.0, 0.0),
+            charge_factor: 0.0,
+        };
+        apu.set_sample_rate(sample_rate);
+
+        // Register values left behind by the boot ROM
+        apu.write(0xFF26, 0xF1);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF25, 0xF3);
+        apu.write(0xFF10, 0x80);
+        apu.write(0xFF11, 0xBF);
+        apu.write(0xFF12, 0xF3);
+        apu.ch1.enabled = false;
+
+        apu
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+        self.charge_factor = 0.999958f32.powf(CPU_CLOCK as f32 / self.sample_rate as f32);
+        self.sample_timer = 0;
+    }
+
+    pub fn samples_buffered(&self) -> usize {
+        // Number of buffered stereo sample pairs
+        self.samples.len() / 2
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        // Take all generated samples as interleaved (left, right) pairs in the range -1.0 to 1.0
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF26 => {
+                let status = (self.ch1.enabled as u8)
+                    | (self.ch2.enabled as u8) << 1
+                    | (self.ch3.enabled as u8) << 2
+                    | (self.ch4.enabled as u8) << 3;
+                (self.powered as u8) << 7 | READ_MASKS[0x16] | status
+            }
+            0xFF10..=0xFF25 => {
+                let i = (addr - 0xFF10) as usize;
+                self.regs[i] | READ_MASKS[i]
+            }
+            0xFF30..=0xFF3F => self.ch3.wave_ram[(addr - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF26 => self.set_power(value & 0x80 != 0),
+            // Wave RAM can always be written
+            0xFF30..=0xFF3F => self.ch3.wave_ram[(addr - 0xFF30) as usize] = value,
+            0xFF10..=0xFF25 => {
+                if !self.powered {
+                    // While powered off, registers can't be written, except for the length counters
+                    match addr {
+                        0xFF11 => self.ch1.length.load(value),
+                        0xFF16 => self.ch2.length.load(value),
+                        0xFF1B => self.ch3.length.load(value),
+                        0xFF20 => self.ch4.length.load(value),
+                        _ => (),
+                    }
+                    return;
+                }
+
+                self.regs[(addr - 0xFF10) as usize] = value;
+                match addr {
+                    0xFF10..=0xFF14 => self.ch1.write((addr - 0xFF10) as u8, value),
+                    0xFF15..=0xFF19 => self.ch2.write((addr - 0xFF15) as u8, value),
+                    0xFF1A..=0xFF1E => self.ch3.write((addr - 0xFF1A) as u8, value),
+                    0xFF1F..=0xFF23 => self.ch4.write((addr - 0xFF1F) as u8, value),
+                    _ => (),    // NR50/NR51 are only used during mixing
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if self.powered && !on {
+            // Powering off clears every register (wave RAM is kept)
+            for addr in 0xFF10..=0xFF25 {
+                self.write(addr, 0);
+            }
+            self.ch1.enabled = false;
+            self.ch2.enabled = false;
+            self.ch3.enabled = false;
+            self.ch4.enabled = false;
+        } else if !self.powered && on {
+            self.frame_sequencer_step = 0;
+            self.frame_sequencer_timer = FRAME_SEQUENCER_PERIOD;
+            self.ch1.duty_position = 0;
+            self.ch2.duty_position = 0;
+            self.ch3.sample = 0;
+        }
+        self.powered = on;
+    }
+
+    pub fn tick(&mut self, m_cycles: u8) {
+        let t_cycles = m_cycles as u32 * 4;
+
+        if self.powered {
+            if self.frame_sequencer_timer <= t_cycles {
+                self.frame_sequencer_timer += FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+            self.frame_sequencer_timer -= t_cycles;
+
+            self.ch1.step(t_cycles);
+            self.ch2.step(t_cycles);
+            self.ch3.step(t_cycles);
+            self.ch4.step(t_cycles);
+        }
+
+        // Output a sample every CPU_CLOCK / sample_rate t-cycles
+        self.sample_timer += self.sample_rate * t_cycles;
+        while self.sample_timer >= CPU_CLOCK {
+            self.sample_timer -= CPU_CLOCK;
+            self.push_sample();
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        // Step:   0   1   2   3   4   5   6   7
+        // Length: x       x       x       x
+        // Sweep:          x               x
+        // Volume:                             x
+        if self.frame_sequencer_step & 0x01 == 0 {
+            if self.ch1.length.clock() { self.ch1.enabled = false; }
+            if self.ch2.length.clock() { self.ch2.enabled = false; }
+            if self.ch3.length.clock() { self.ch3.enabled = false; }
+            if self.ch4.length.clock() { self.ch4.enabled = false; }
+        }
+        if self.frame_sequencer_step == 2 || self.frame_sequencer_step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if self.frame_sequencer_step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn push_sample(&mut self) {
+        let (mut left, mut right) = (0.0, 0.0);
+
+        if self.powered {
+            let nr50 = self.regs[0x14];
+            let nr51 = self.regs[0x15];
+            let channels = [
+                (self.ch1.dac_enabled, self.ch1.output()),
+                (self.ch2.dac_enabled, self.ch2.output()),
+                (self.ch3.dac_enabled, self.ch3.output()),
+                (self.ch4.dac_enabled, self.ch4.output()),
+            ];
+
+            for (i, (dac_enabled, output)) in channels.iter().enumerate() {
+                // Each DAC maps a digital 0-15 value to an analog -1.0 to 1.0 (or nothing, when off)
+                if !dac_enabled { continue; }
+                let analog = *output as f32 / 7.5 - 1.0;
+                if nr51 & (0x10 << i) != 0 { left += analog; }
+                if nr51 & (0x01 << i) != 0 { right += analog; }
+            }
+
+            // NR50 sets the master volume of each side from 1/8 to 8/8
+            left *= (((nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+            right *= ((nr50 & 0x07) + 1) as f32 / 8.0;
+            left /= 4.0;
+            right /= 4.0;
+        }
+
+        let left = self.high_pass(left, true);
+        let right = self.high_pass(right, false);
+
+        // Don't let the buffer grow forever if nobody is taking samples; keep about a second of audio
+        if self.samples.len() >= self.sample_rate as usize * 2 {
+            self.samples.drain(..self.sample_rate as usize);
+        }
+        self.samples.push(left);
+        self.samples.push(right);
+    }
+
+    fn high_pass(&mut self, input: f32, left: bool) -> f32 {
+        let capacitor = if left { &mut self.capacitor.0 } else { &mut self.capacitor.1 };
+        let output = input - *capacitor;
+        *capacitor = input - output * self.charge_factor;
+        output
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.enabled = r.read_bool()?;
+        self.counter = r.read_u16()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.initial_volume);
+        w.write_bool(self.increase);
+        w.write_u8(self.period);
+        w.write_u8(self.volume);
+        w.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.initial_volume = r.read_u8()?;
+        self.increase = r.read_bool()?;
+        self.period = r.read_u8()?;
+        self.volume = r.read_u8()?;
+        self.timer = r.read_u8()?;
+        if self.initial_volume > 15 || self.volume > 15 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+impl Savestate for Sweep {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.period);
+        w.write_bool(self.negate);
+        w.write_u8(self.shift);
+        w.write_u8(self.timer);
+        w.write_bool(self.enabled);
+        w.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.period = r.read_u8()?;
+        self.negate = r.read_bool()?;
+        self.shift = r.read_u8()?;
+        self.timer = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        self.shadow_frequency = r.read_u16()?;
+        if self.shift > 7 || self.shadow_frequency > 2047 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+impl Savestate for SquareChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.dac_enabled);
+        w.write_u8(self.duty);
+        w.write_u8(self.duty_position);
+        w.write_u16(self.frequency);
+        w.write_i32(self.timer);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.enabled = r.read_bool()?;
+        self.dac_enabled = r.read_bool()?;
+        self.duty = r.read_u8()?;
+        self.duty_position = r.read_u8()?;
+        self.frequency = r.read_u16()?;
+        self.timer = r.read_i32()?;
+        if self.duty > 3 || self.duty_position > 7 || self.frequency > 2047 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        // Whether a channel has a sweep unit is fixed, so it doesn't need to be stored
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Savestate for WaveChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.dac_enabled);
+        w.write_u8(self.volume_code);
+        w.write_u16(self.frequency);
+        w.write_i32(self.timer);
+        w.write_u8(self.position);
+        w.write_u8(self.sample);
+        self.length.save_state(w);
+        w.write_bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.enabled = r.read_bool()?;
+        self.dac_enabled = r.read_bool()?;
+        self.volume_code = r.read_u8()?;
+        self.frequency = r.read_u16()?;
+        self.timer = r.read_i32()?;
+        self.position = r.read_u8()?;
+        self.sample = r.read_u8()?;
+        if self.frequency > 2047 || self.position > 31 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.length.load_state(r)?;
+        r.read_bytes(&mut self.wave_ram)
+    }
+}
+
+impl Savestate for NoiseChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.dac_enabled);
+        w.write_u8(self.clock_shift);
+        w.write_bool(self.width_7bit);
+        w.write_u8(self.divisor_code);
+        w.write_i32(self.timer);
+        w.write_u16(self.lfsr);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.enabled = r.read_bool()?;
+        self.dac_enabled = r.read_bool()?;
+        self.clock_shift = r.read_u8()?;
+        self.width_7bit = r.read_bool()?;
+        self.divisor_code = r.read_u8()?;
+        self.timer = r.read_i32()?;
+        self.lfsr = r.read_u16()?;
+        // Anything the registers can't produce would overflow the period calculation
+        if self.clock_shift > 15 || self.divisor_code > 7 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)
+    }
+}
+
+impl Savestate for Apu {
+    // The output sample rate, buffered samples and filter are host-side and aren't stored
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.powered);
+        w.write_bytes(&self.regs);
+        self.ch1.save_state(w);
+        self.ch2.save_state(w);
+        self.ch3.save_state(w);
+        self.ch4.save_state(w);
+        w.write_u32(self.frame_sequencer_timer);
+        w.write_u8(self.frame_sequencer_step);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.powered = r.read_bool()?;
+        r.read_bytes(&mut self.regs)?;
+        self.ch1.load_state(r)?;
+        self.ch2.load_state(r)?;
+        self.ch3.load_state(r)?;
+        self.ch4.load_state(r)?;
+        self.frame_sequencer_timer = r.read_u32()?;
+        self.frame_sequencer_step = r.read_u8()?;
+        if self.frame_sequencer_step > 7 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_state_is_rejected() {
+        let mut w = StateWriter::new();
+        Apu::default().save_state(&mut w);
+        let mut data = w.into_bytes();
+        assert!(Apu::default().load_state(&mut StateReader::new(&data)).is_ok());
+
+        // Channel 1's duty, after the power flag, the registers and two flags of the channel
+        data[1 + 0x17 + 2] = 4;
+        assert!(matches!(Apu::default().load_state(&mut StateReader::new(&data)), Err(SavestateError::InvalidFormat)));
+    }
+}
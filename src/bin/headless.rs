@@ -0,0 +1,260 @@
+// Runs a ROM without opening a window, as fast as possible.
+// Meant for CI and scripted runs: the exit code tells whether the run succeeded.
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rgbl::serial::CaptureSerial;
+use rgbl::{AccessMode, JoypadButton, Lcd, Motherboard, RenderMode, LCD_HEIGHT, LCD_WIDTH};
+
+// Exit codes
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_TIMEOUT: i32 = 1;
+// The emulator panicked
+const EXIT_CRASH: i32 = 2;
+// Invalid arguments, or a ROM that couldn't be loaded
+const EXIT_USAGE: i32 = 3;
+
+// Used when neither a frame limit nor a stop condition is given: one minute of emulated time
+const DEFAULT_FRAMES: u64 = 3600;
+
+const USAGE: &str = "usage: headless <rom> [options]
+
+options:
+    --frames N            run for at most N frames (default 3600)
+    --until-serial TEXT   stop successfully once TEXT has been sent over the serial port
+    --input FILE          feed joypad input from a script
+    --png FILE            write the final frame to FILE
+    --png-every N         also write every Nth frame, numbered, next to the --png FILE
+    --serial-out FILE     write everything sent over the serial port to FILE
+    --fifo                draw with the dot-accurate pixel FIFO renderer (slower)
+    --lenient             allow VRAM and OAM access while the PPU is using them
+
+input scripts have one event per line: `<frame> press|release <button>`,
+where button is one of up, down, left, right, a, b, start, select.
+lines starting with # are ignored.";
+
+struct Options {
+    rom: PathBuf,
+    frames: Option<u64>,
+    until_serial: Option<String>,
+    input: Option<PathBuf>,
+    png: Option<PathBuf>,
+    png_every: Option<u64>,
+    serial_out: Option<PathBuf>,
+    fifo: bool,
+    lenient: bool,
+}
+
+struct InputEvent {
+    frame: u64,
+    press: bool,
+    button: JoypadButton,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| usage_error(&e));
+    let inputs = match &options.input {
+        Some(path) => parse_input_script(path).unwrap_or_else(|e| usage_error(&e)),
+        None => Vec::new(),
+    };
+    let rom = fs::read(&options.rom)
+        .unwrap_or_else(|e| fail(&format!("could not read {}: {}", options.rom.display(), e)));
+
+    let serial = CaptureSerial::new();
+    let mut mb = Motherboard::new(&rom)
+        .unwrap_or_else(|e| fail(&format!("could not load {}: {}", options.rom.display(), e)));
+    mb.set_serial_device(Box::new(serial.clone()));
+    if options.fifo {
+        mb.set_render_mode(RenderMode::Fifo);
+    }
+    if options.lenient {
+        mb.set_access_mode(AccessMode::Lenient);
+    }
+
+    // A panic inside the emulator is reported through the exit code
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run(&mut mb, &options, &inputs, &serial)));
+
+    if let Some(path) = &options.serial_out {
+        if let Err(e) = fs::write(path, serial.output()) {
+            eprintln!("could not write serial output to {}: {}", path.display(), e);
+        }
+    }
+
+    let code = match result {
+        Ok(Ok(code)) => code,
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            EXIT_USAGE
+        }
+        Err(_) => {
+            eprintln!("emulator panicked at PC {:04X}", mb.cpu.pc);
+            EXIT_CRASH
+        }
+    };
+    process::exit(code);
+}
+
+fn run(mb: &mut Motherboard, options: &Options, inputs: &[InputEvent], serial: &CaptureSerial) -> Result<i32, String> {
+    let frames = options.frames.unwrap_or(DEFAULT_FRAMES);
+    let mut inputs = inputs.iter().peekable();
+
+    for frame in 0..frames {
+        while let Some(event) = inputs.next_if(|e| e.frame <= frame) {
+            if event.press { mb.joypad_mut().press(event.button) } else { mb.joypad_mut().release(event.button) }
+        }
+
+        // A locked up CPU is reported, but the rest of the system keeps running like the hardware
+        if let Err(e) = mb.run_frame() {
+            eprintln!("{}", e);
+        }
+        // Nothing is listening for audio, so don't let it pile up
+        mb.take_audio_samples();
+
+        if let (Some(path), Some(every)) = (&options.png, options.png_every) {
+            if (frame + 1) % every == 0 {
+                write_png(mb.lcd(), &numbered_path(path, frame + 1))?;
+            }
+        }
+
+        if let Some(text) = &options.until_serial {
+            if String::from_utf8_lossy(&serial.output()).contains(text.as_str()) {
+                finish(mb, options)?;
+                return Ok(EXIT_SUCCESS);
+            }
+        }
+    }
+
+    finish(mb, options)?;
+    // Running out of frames is only a failure if we were waiting for something
+    Ok(if options.until_serial.is_some() { EXIT_TIMEOUT } else { EXIT_SUCCESS })
+}
+
+fn finish(mb: &Motherboard, options: &Options) -> Result<(), String> {
+    match &options.png {
+        Some(path) => write_png(mb.lcd(), path),
+        None => Ok(()),
+    }
+}
+
+fn numbered_path(path: &Path, frame: u64) -> PathBuf {
+    // frame.png -> frame_000120.png
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{:06}.{}", stem, frame, ext.to_string_lossy()),
+        None => format!("{}_{:06}", stem, frame),
+    };
+    path.with_file_name(name)
+}
+
+fn write_png(lcd: &Lcd, path: &Path) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("could not write {}: {}", path.display(), e);
+
+    // Same shades as the windowed frontend
+    let data: Vec<u8> = lcd.pixels.iter().map(|&c| match c {
+        3 => 0,
+        2 => 100,
+        1 => 175,
+        _ => 255,
+    }).collect();
+
+    let file = File::create(path).map_err(|e| error(&e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), LCD_WIDTH as u32, LCD_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| error(&e))?;
+    writer.write_image_data(&data).map_err(|e| error(&e))
+}
+
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        rom: PathBuf::new(), frames: None, until_serial: None, input: None, png: None, png_every: None, serial_out: None, fifo: false, lenient: false,
+    };
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--frames" => options.frames = Some(parse_count(&value()?)?),
+            "--until-serial" => options.until_serial = Some(value()?),
+            "--input" => options.input = Some(PathBuf::from(value()?)),
+            "--png" => options.png = Some(PathBuf::from(value()?)),
+            "--png-every" => options.png_every = Some(parse_count(&value()?)?),
+            "--serial-out" => options.serial_out = Some(PathBuf::from(value()?)),
+            "--fifo" => options.fifo = true,
+            "--lenient" => options.lenient = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(EXIT_SUCCESS);
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_none() => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if options.png_every.is_some() && options.png.is_none() {
+        return Err("--png-every needs --png".to_string());
+    }
+    options.rom = rom.ok_or("no ROM given")?;
+    Ok(options)
+}
+
+fn parse_count(s: &str) -> Result<u64, String> {
+    match s.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid count {}", s)),
+    }
+}
+
+fn parse_input_script(path: &Path) -> Result<Vec<InputEvent>, String> {
+    let script = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    let mut events = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+
+        let error = || format!("{}:{}: invalid input event \"{}\"", path.display(), i + 1, line);
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 3 { return Err(error()); }
+
+        let frame = parts[0].parse().map_err(|_| error())?;
+        let press = match parts[1] {
+            "press" => true,
+            "release" => false,
+            _ => return Err(error()),
+        };
+        let button = match parts[2].to_lowercase().as_str() {
+            "up" => JoypadButton::Up,
+            "down" => JoypadButton::Down,
+            "left" => JoypadButton::Left,
+            "right" => JoypadButton::Right,
+            "a" => JoypadButton::A,
+            "b" => JoypadButton::B,
+            "start" => JoypadButton::Start,
+            "select" => JoypadButton::Select,
+            _ => return Err(error()),
+        };
+        events.push(InputEvent { frame, press, button });
+    }
+
+    // Events are applied in frame order
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(EXIT_USAGE);
+}
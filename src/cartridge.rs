@@ -1,51 +1,97 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use enum_dispatch::enum_dispatch;
 
-const RAM_SIZES: [usize ; 6] = [0, 0, 8192, 32768, 131072, 65536];
+use crate::header::{CartridgeError, CartridgeHeader, CartridgeKind, NINTENDO_LOGO};
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
 
 #[enum_dispatch(CartridgeType)]
 pub trait Cartridge {
     fn read(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, value: u8);
+
+    // Battery-backed cartridges keep their RAM contents after power-off.
+    // save_data/load_save_data give the contents of a .sav file for the cartridge.
+    fn has_battery(&self) -> bool { false }
+    fn save_data(&self) -> Vec<u8> { Vec::new() }
+    fn load_save_data(&mut self, _data: &[u8]) {}
+    // What save_data depends on apart from the passing of time (a running clock, the footer's timestamp).
+    // The .sav file only needs rewriting when this changes.
+    fn save_data_key(&self) -> Vec<u8> { self.save_data() }
+
+    // Advance any cartridge hardware that runs on the system clock (e.g. a real-time clock)
+    fn tick(&mut self, _m_cycles: u8) {}
+
+    // Whether the cartridge's rumble motor is currently switched on
+    fn rumble(&self) -> bool { false }
+
+    // The ROM bank currently mapped at a ROM address (0x0000-0x7FFF)
+    fn rom_bank(&self, addr: u16) -> usize { if addr < 0x4000 { 0 } else { 1 } }
 }
 
-pub fn load_cartridge(rom: &Vec<u8>) -> CartridgeType {
-    // Build cartridge struct from ROM info
-    let title: &[u8] = &rom[0x0134..0x0143];
-    let licensee_code: &[u8] = &rom[0x0144..0x0145];
-    let cart_type: u8 = rom[0x0147];
-    let rom_size: usize = 0x8000 << rom[0x0148];
-    let ram_size: usize = RAM_SIZES[rom[0x0149] as usize];
+pub fn load_cartridge(rom: &[u8], header: &CartridgeHeader) -> Result<CartridgeType, CartridgeError> {
+    // Build cartridge struct from ROM info, given its parsed header
+    let ram_size = header.ram_size;
+    let battery = header.kind.has_battery();
 
-    match cart_type {
-        0x00 => CartridgeType::NoMBC(NoMBC::new(rom)),
-        0x01..=0x03 => CartridgeType::MBC1(MBC1::new(rom, ram_size)),
-        0x0F..=0x13 => CartridgeType::MBC3(MBC3::new(rom, ram_size)),
-        _ => panic!("Invalid cartridge type {}", cart_type),
-    }
-    
+    let cartridge = match header.kind {
+        CartridgeKind::RomOnly => CartridgeType::NoMBC(NoMBC::new(rom)),
+        CartridgeKind::Mbc1 | CartridgeKind::Mbc1Ram | CartridgeKind::Mbc1RamBattery =>
+            CartridgeType::MBC1(MBC1::new(rom, ram_size, battery, is_mbc1_multicart(rom))),
+        CartridgeKind::Mbc2 | CartridgeKind::Mbc2Battery => CartridgeType::MBC2(MBC2::new(rom, battery)),
+        CartridgeKind::Mbc3TimerBattery | CartridgeKind::Mbc3TimerRamBattery | CartridgeKind::Mbc3
+            | CartridgeKind::Mbc3Ram | CartridgeKind::Mbc3RamBattery =>
+            CartridgeType::MBC3(MBC3::new(rom, ram_size, battery, header.kind.has_timer())),
+        CartridgeKind::Mbc5 | CartridgeKind::Mbc5Ram | CartridgeKind::Mbc5RamBattery | CartridgeKind::Mbc5Rumble
+            | CartridgeKind::Mbc5RumbleRam | CartridgeKind::Mbc5RumbleRamBattery =>
+            CartridgeType::MBC5(MBC5::new(rom, ram_size, battery, header.kind.has_rumble())),
+        kind => return Err(CartridgeError::UnsupportedType(kind)),
+    };
+    Ok(cartridge)
+}
+
+fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    // MBC1M multicarts are 1 MiB compilations of 256 KiB games. Each game has its own header,
+    // so the second game's Nintendo logo shows up at the start of bank 0x10.
+    let logo_addr = 0x10 * 16384 + 0x0104;
+    rom.len() == 0x100000 && rom[logo_addr..logo_addr + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+}
+
+fn load_ram(ram: &mut [u8], data: &[u8]) {
+    // Copy save data into cartridge RAM, ignoring any bytes that don't fit
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
 }
 
 #[enum_dispatch]
+#[derive(Clone)]
 pub enum CartridgeType {
     NoMBC,
     MBC1,
+    MBC2,
     MBC3,
+    MBC5,
 }
 
 
+#[derive(Clone)]
 pub struct NoMBC {
     rom: Vec<u8>
 }
 
 impl NoMBC {
-    pub fn new(rom: &Vec<u8>) -> Self {
-        return NoMBC {rom: rom.to_vec()};
+    pub fn new(rom: &[u8]) -> Self {
+        NoMBC {rom: rom.to_vec()}
     }
 }
 
 impl Cartridge for NoMBC {
     fn read(&self, addr: u16) -> u8 {
-        return self.rom[addr as usize];
+        match addr {
+            0x0000..=0x7FFF => self.rom[addr as usize],
+            // There is no cartridge RAM, so nothing drives the bus
+            _ => 0xFF,
+        }
     }
 
     fn write(&mut self, _: u16, _: u8) {
@@ -54,90 +100,361 @@ impl Cartridge for NoMBC {
 }
 
 
+#[derive(Clone)]
 pub struct MBC1 {
-    rom_size: usize,
-    ram_size: usize,
     rom: Vec<u8>,
     ram: Vec<u8>,
-    active_rom_bank: usize,
-    active_ram_bank: usize,
+    rom_banks: usize,
+    // BANK1 (5 bits, written at 0x2000-0x3FFF) is the low part of the ROM bank number.
+    // BANK2 (2 bits, written at 0x4000-0x5FFF) is either the upper ROM bank bits or the RAM bank number.
+    bank1: u8,
+    bank2: u8,
     ram_active: bool,
+    // false: mode 0, BANK2 only affects 0x4000-0x7FFF
+    // true: mode 1, BANK2 also affects 0x0000-0x3FFF and selects the RAM bank
     banking_mode: bool,
+    battery: bool,
+    // MBC1M multicarts only wire up the low 4 bits of BANK1, so BANK2 selects between 256 KiB games
+    multicart: bool,
 }
 
 impl MBC1 {
-    pub fn new(rom: &Vec<u8>, ram_size: usize) -> Self {
+    pub fn new(rom: &[u8], ram_size: usize, battery: bool, multicart: bool) -> Self {
         let cartrom: Vec<u8> = rom.to_vec();
         let cartram: Vec<u8> = vec![0; ram_size];
-        let cart: MBC1 = MBC1 {rom_size: cartrom.len(), ram_size, rom: cartrom, ram: cartram, 
-                                active_rom_bank: 1, active_ram_bank: 0, ram_active: false, banking_mode: false};
-        return cart;
+        let rom_banks = (cartrom.len() / 16384).max(1);
+        MBC1 {rom: cartrom, ram: cartram, rom_banks, bank1: 1, bank2: 0, ram_active: false, banking_mode: false, battery, multicart}
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn bank1_mask(&self) -> u8 {
+        if self.multicart { 0x0F } else { 0x1F }
+    }
+
+    fn rom_addr(&self, addr: u16) -> usize {
+        self.rom_bank(addr) * 16384 + (addr & 0x3FFF) as usize
+    }
+
+    fn ram_addr(&self, addr: u16) -> usize {
+        let bank = if self.banking_mode { self.bank2 as usize } else { 0 };
+        (bank * 8192 + (addr - 0xA000) as usize) % self.ram.len()
     }
 }
 
 impl Cartridge for MBC1 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x3FFF => self.rom[addr as usize],
-            0x4000..=0x7FFF => self.rom[self.active_rom_bank * 16384 + (addr - 0x4000) as usize],
-            0xA000..=0xBFFF => self.ram[self.active_ram_bank * 16384 + (addr - 0xA000) as usize],
-            _ => panic!("Tried to read invalid address on MBC1 cartridge: {}", addr)
+            0x0000..=0x7FFF => self.rom[self.rom_addr(addr)],
+            0xA000..=0xBFFF if self.ram_active && !self.ram.is_empty() => self.ram[self.ram_addr(addr)],
+            // Reading RAM while it is disabled (or absent) gives open-bus 0xFF
+            _ => 0xFF,
         }
     }
 
     fn write(&mut self, addr: u16, value: u8) {
-        if addr < 0x2000 {
-            // Writing to addresses 0x0000 to 0x1fff sets the external RAM active state
+        match addr {
             // Any value written with a low four bits of 0xA will set the RAM active, others deactivate
-            self.ram_active = value & 0xF == 0xA;
-        } else if addr >= 0x2000 && addr < 0x4000 {
-            // Writing between 0x2000 and 0x3fff sets the active ROM bank
-            // but, it only sets the bottom 5 bits!
-            // If all the used bits of the value are 0, increment it by one. 
-            let bank_value = if value & 0x1F == 0 { 0x1 } else { value };
-            self.active_rom_bank = ((bank_value & 0x1F) | (self.active_rom_bank as u8 & 0xE0)) as usize;
-        } else if addr >= 0x4000 && addr < 0x6000 {
-            // Writing betweeen 0x4000 and 0x5fff sets the top 2 bits 
-            // of the active ROM bank if the ROM is big enough, or sets 
-            // the active RAM bank if the RAM is big enough.
-
-            // The effect of this write is determined by the current banking mode, set via writes above 0x6000.
-
-            // The upper ROM bits are only valid with more than 1 mb of ROM
-            if self.banking_mode == false && self.rom_size >= 1048576 {
-                self.active_ram_bank = (value & 0x3) as usize;
-            // Can only set active RAM bank on 32 kb RAM carts
-            } else if self.banking_mode == true && self.ram_size == 32768 {
-                self.active_rom_bank = ((value & 0x60) | (self.active_rom_bank as u8 & 0x9f)) as usize;
+            0x0000..=0x1FFF => self.ram_active = value & 0x0F == 0x0A,
+            // Only the bottom 5 bits are stored. If they are all 0, the register reads as 1 instead,
+            // so banks 0x00/0x20/0x40/0x60 can't be selected in the switchable region.
+            0x2000..=0x3FFF => self.bank1 = if value & 0x1F == 0 { 1 } else { value & 0x1F },
+            0x4000..=0x5FFF => self.bank2 = value & 0x03,
+            0x6000..=0x7FFF => self.banking_mode = value & 0x01 != 0,
+            0xA000..=0xBFFF if self.ram_active && !self.ram.is_empty() => {
+                let ram_addr = self.ram_addr(addr);
+                self.ram[ram_addr] = value;
+            }
+            _ => (),
+        }
+    }
+
+    fn has_battery(&self) -> bool { self.battery }
+
+    fn save_data(&self) -> Vec<u8> { self.ram.clone() }
+
+    fn load_save_data(&mut self, data: &[u8]) { load_ram(&mut self.ram, data); }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        let bank = if addr < 0x4000 {
+            // In mode 1, BANK2 selects which bank appears in the "fixed" region (only matters on ROMs >= 1 MiB)
+            if self.banking_mode { (self.bank2 as usize) << self.bank2_shift() } else { 0 }
+        } else {
+            ((self.bank2 as usize) << self.bank2_shift()) | (self.bank1 & self.bank1_mask()) as usize
+        };
+        // Bank numbers wrap around to the actual size of the ROM
+        bank % self.rom_banks
+    }
+}
+
+
+#[derive(Clone)]
+pub struct MBC2 {
+    rom: Vec<u8>,
+    // 512 half-bytes of RAM built into the MBC itself; only the low nibble of each byte is used
+    ram: Vec<u8>,
+    rom_banks: usize,
+    active_rom_bank: usize,
+    ram_active: bool,
+    battery: bool,
+}
+
+impl MBC2 {
+    pub fn new(rom: &[u8], battery: bool) -> Self {
+        let cartrom: Vec<u8> = rom.to_vec();
+        let rom_banks = (cartrom.len() / 16384).max(1);
+        MBC2 {rom: cartrom, ram: vec![0; 512], rom_banks, active_rom_bank: 1, ram_active: false, battery}
+    }
+}
+
+impl Cartridge for MBC2 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+            0x4000..=0x7FFF => self.rom[self.rom_bank(addr) * 16384 + (addr - 0x4000) as usize],
+            // Only 9 address bits are connected, so the 512 entries repeat throughout 0xA000-0xBFFF.
+            // The upper nibble isn't connected either, and reads back as all 1s.
+            0xA000..=0xBFFF if self.ram_active => 0xF0 | self.ram[(addr & 0x01FF) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            // Bit 8 of the address decides which register is written:
+            // clear for the RAM enable register, set for the ROM bank register
+            0x0000..=0x3FFF => {
+                if addr & 0x0100 == 0 {
+                    self.ram_active = value & 0x0F == 0x0A;
+                } else {
+                    self.active_rom_bank = if value & 0x0F == 0 { 1 } else { (value & 0x0F) as usize };
+                }
+            }
+            0x4000..=0x7FFF => (),
+            0xA000..=0xBFFF if self.ram_active => self.ram[(addr & 0x01FF) as usize] = value & 0x0F,
+            _ => (),
+        }
+    }
+
+    fn has_battery(&self) -> bool { self.battery }
+
+    fn save_data(&self) -> Vec<u8> { self.ram.clone() }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+        for v in self.ram.iter_mut() { *v &= 0x0F; }
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        if addr < 0x4000 { 0 } else { self.active_rom_bank % self.rom_banks }
+    }
+}
+
+
+// The RTC counts seconds of emulated time: one second is 2^20 m-cycles
+const RTC_MCYCLES_PER_SECOND: u32 = 1 << 20;
+
+#[derive(Clone, Copy, Default)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days_low: u8,
+    // Bit 0: day counter bit 8, bit 6: halt, bit 7: day counter carry
+    days_high: u8,
+}
+
+impl RtcRegisters {
+    fn days(&self) -> u16 {
+        ((self.days_high as u16 & 0x01) << 8) | self.days_low as u16
+    }
+
+    fn set_days(&mut self, days: u16) {
+        self.days_low = (days & 0xFF) as u8;
+        self.days_high = (self.days_high & 0xFE) | ((days >> 8) & 0x01) as u8;
+    }
+
+    fn halted(&self) -> bool {
+        self.days_high & 0x40 != 0
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    fn read(&self, reg: u8) -> u8 {
+        match reg {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days_low,
+            0x0C => self.days_high,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        // Only the bits that exist in each register are kept
+        match reg {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days_low = value,
+            0x0C => self.days_high = value & 0xC1,
+            _ => (),
+        }
+    }
+
+    fn tick_second(&mut self) {
+        // Registers holding out-of-range values count up to their bit width and wrap to 0 without carrying
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 { return; }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 { return; }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 { return; }
+        self.hours = 0;
+
+        let days = self.days() + 1;
+        if days > 0x1FF {
+            // Day counter overflowed: set the carry bit, which stays set until the game clears it
+            self.days_high |= 0x80;
+        }
+        self.set_days(days & 0x1FF);
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // Step through any out-of-range values one second at a time, then jump ahead arithmetically
+        while seconds > 0 && !self.in_range() {
+            self.tick_second();
+            seconds -= 1;
+        }
+        if seconds == 0 { return; }
+
+        let total = self.seconds as u64 + 60 * (self.minutes as u64 + 60 * (self.hours as u64 + 24 * self.days() as u64)) + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF { self.days_high |= 0x80; }
+        self.set_days((days & 0x1FF) as u16);
+    }
+}
+
+#[derive(Clone)]
+struct Rtc {
+    regs: RtcRegisters,
+    latched: RtcRegisters,
+    // m-cycles counted towards the next second
+    cycles: u32,
+    // Latching happens on a write of 0x00 followed by a write of 0x01
+    latch_armed: bool,
+    // Number of register writes by the game, so setting the clock counts as a change to the save
+    writes: u32,
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Rtc { regs: RtcRegisters::default(), latched: RtcRegisters::default(), cycles: 0, latch_armed: false, writes: 0 }
+    }
+
+    fn tick(&mut self, m_cycles: u8) {
+        if self.regs.halted() { return; }
+
+        self.cycles += m_cycles as u32;
+        while self.cycles >= RTC_MCYCLES_PER_SECOND {
+            self.cycles -= RTC_MCYCLES_PER_SECOND;
+            self.regs.tick_second();
+        }
+    }
+
+    fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.latched = self.regs;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        // Writing the seconds register also resets the sub-second counter
+        if reg == 0x08 { self.cycles = 0; }
+        self.regs.write(reg, value);
+        self.writes = self.writes.wrapping_add(1);
+    }
+
+    // Save files store the RTC in the footer format used by VBA-M, BGB and others:
+    // the live then latched registers as five little-endian u32s each, followed by a u64 UNIX timestamp.
+    // Some emulators write a 32 bit timestamp instead, giving a 44 byte footer.
+    fn footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(48);
+        for regs in [&self.regs, &self.latched] {
+            for reg in 0x08..=0x0C {
+                footer.extend_from_slice(&(regs.read(reg) as u32).to_le_bytes());
             }
-            // This write does nothing if neither of the above conditions are met
-        } else if addr >= 0x6000 && addr < 0x8000 {
-            // Set the banking mode: 0 indicates ROM banking mode (default) and 1 is RAM banking mode
-            self.banking_mode = value == 0x1;
+        }
+        footer.extend_from_slice(&unix_timestamp().to_le_bytes());
+        footer
+    }
+
+    fn load_footer(&mut self, footer: &[u8]) {
+        let word = |i: usize| u32::from_le_bytes([footer[i*4], footer[i*4+1], footer[i*4+2], footer[i*4+3]]) as u8;
+        for reg in 0x08..=0x0C {
+            let i = (reg - 0x08) as usize;
+            self.regs.write(reg, word(i));
+            self.latched.write(reg, word(i + 5));
+        }
+
+        let saved_at = if footer.len() >= 48 {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&footer[40..48]);
+            u64::from_le_bytes(bytes)
+        } else {
+            u32::from_le_bytes([footer[40], footer[41], footer[42], footer[43]]) as u64
+        };
+
+        // Catch the clock up on the real time that passed while the emulator wasn't running
+        if !self.regs.halted() {
+            self.regs.advance(unix_timestamp().saturating_sub(saved_at));
         }
     }
 }
 
+fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
 
+#[derive(Clone)]
 pub struct MBC3 {
-    // TODO: Finish this
-    rom_size: usize,
-    ram_size: usize,
     rom: Vec<u8>,
     ram: Vec<u8>,
+    rom_banks: usize,
     active_rom_bank: usize,
-    active_ram_bank: usize,
+    // 0x00-0x07 select a RAM bank, 0x08-0x0C select an RTC register
+    ram_bank_select: u8,
     ram_active: bool,
-    banking_mode: bool,
+    battery: bool,
+    rtc: Option<Rtc>,
 }
 
 impl MBC3 {
-    pub fn new(rom: &Vec<u8>, ram_size: usize) -> Self {
+    pub fn new(rom: &[u8], ram_size: usize, battery: bool, timer: bool) -> Self {
         let cartrom: Vec<u8> = rom.to_vec();
         let cartram: Vec<u8> = vec![0; ram_size];
-        let cart: MBC3 = MBC3 {rom_size: cartrom.len(), ram_size, rom: cartrom, ram: cartram, 
-                                active_rom_bank: 1, active_ram_bank: 0, ram_active: false, banking_mode: false};
-        return cart;
+        let rom_banks = (cartrom.len() / 16384).max(1);
+        MBC3 {rom: cartrom, ram: cartram, rom_banks, active_rom_bank: 1, ram_bank_select: 0, ram_active: false, battery,
+                rtc: if timer { Some(Rtc::new()) } else { None }}
+    }
+
+    fn ram_addr(&self, addr: u16) -> usize {
+        // RAM banks are 8 KiB; wrap around if the game selects a bank the cartridge doesn't have
+        (self.ram_bank_select as usize * 8192 + (addr - 0xA000) as usize) % self.ram.len()
     }
 }
 
@@ -145,28 +462,324 @@ impl Cartridge for MBC3 {
     fn read(&self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x3FFF => self.rom[addr as usize],
-            0x4000..=0x7FFF => self.rom[self.active_rom_bank * 16384 + (addr - 0x4000) as usize],
-            0xA000..=0xBFFF => self.ram[self.active_ram_bank * 16384 + (addr - 0xA000) as usize],
-            _ => panic!("Tried to read invalid address on MBC3 cartridge: {}", addr)
+            0x4000..=0x7FFF => self.rom[self.rom_bank(addr) * 16384 + (addr - 0x4000) as usize],
+            0xA000..=0xBFFF => {
+                if !self.ram_active { return 0xFF; }
+                match (self.ram_bank_select, &self.rtc) {
+                    (0x00..=0x07, _) if !self.ram.is_empty() => self.ram[self.ram_addr(addr)],
+                    (0x08..=0x0C, Some(rtc)) => rtc.latched.read(self.ram_bank_select),
+                    _ => 0xFF,
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_active = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.active_rom_bank = if value & 0x7F == 0 { 1 } else { (value & 0x7F) as usize },
+            0x4000..=0x5FFF => self.ram_bank_select = value,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc { rtc.write_latch(value); }
+            },
+            0xA000..=0xBFFF => {
+                if !self.ram_active { return; }
+                match self.ram_bank_select {
+                    0x00..=0x07 if !self.ram.is_empty() => {
+                        let ram_addr = self.ram_addr(addr);
+                        self.ram[ram_addr] = value;
+                    }
+                    0x08..=0x0C => if let Some(rtc) = &mut self.rtc { rtc.write(self.ram_bank_select, value); },
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn tick(&mut self, m_cycles: u8) {
+        if let Some(rtc) = &mut self.rtc { rtc.tick(m_cycles); }
+    }
+
+    fn has_battery(&self) -> bool { self.battery }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc { data.extend(rtc.footer()); }
+        data
+    }
+
+    fn save_data_key(&self) -> Vec<u8> {
+        // The clock ticks (and the footer's timestamp changes) every second, only the game setting it counts
+        let mut key = self.ram.clone();
+        if let Some(rtc) = &self.rtc { key.extend_from_slice(&rtc.writes.to_le_bytes()); }
+        key
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.ram, data);
+        if let Some(rtc) = &mut self.rtc {
+            let footer = &data[self.ram.len().min(data.len())..];
+            if footer.len() >= 44 { rtc.load_footer(footer); }
+        }
+    }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        if addr < 0x4000 { 0 } else { self.active_rom_bank % self.rom_banks }
+    }
+}
+
+
+#[derive(Clone)]
+pub struct MBC5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rom_banks: usize,
+    // 9 bit ROM bank number; unlike older MBCs, bank 0 can be mapped to 0x4000-0x7FFF
+    active_rom_bank: usize,
+    active_ram_bank: usize,
+    ram_active: bool,
+    battery: bool,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl MBC5 {
+    pub fn new(rom: &[u8], ram_size: usize, battery: bool, has_rumble: bool) -> Self {
+        let cartrom: Vec<u8> = rom.to_vec();
+        let cartram: Vec<u8> = vec![0; ram_size];
+        let rom_banks = (cartrom.len() / 16384).max(1);
+        MBC5 {rom: cartrom, ram: cartram, rom_banks, active_rom_bank: 1, active_ram_bank: 0, ram_active: false,
+                battery, has_rumble, rumble: false}
+    }
+
+    fn ram_addr(&self, addr: u16) -> usize {
+        (self.active_ram_bank * 8192 + (addr - 0xA000) as usize) % self.ram.len()
+    }
+}
+
+impl Cartridge for MBC5 {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[addr as usize],
+            0x4000..=0x7FFF => self.rom[self.rom_bank(addr) * 16384 + (addr - 0x4000) as usize],
+            0xA000..=0xBFFF if self.ram_active && !self.ram.is_empty() => self.ram[self.ram_addr(addr)],
+            _ => 0xFF,
         }
     }
 
     fn write(&mut self, addr: u16, value: u8) {
         match addr {
             0x0000..=0x1FFF => self.ram_active = value == 0x0A,
-            0x2000..=0x3FFF => self.active_rom_bank = if value == 0 { 1 } else { value as usize },
+            // Low 8 bits of the ROM bank number
+            0x2000..=0x2FFF => self.active_rom_bank = (self.active_rom_bank & 0x100) | value as usize,
+            // Bit 8 of the ROM bank number
+            0x3000..=0x3FFF => self.active_rom_bank = (self.active_rom_bank & 0xFF) | ((value as usize & 0x01) << 8),
             0x4000..=0x5FFF => {
-                if value <= 0x03 {
-                    self.active_ram_bank = value as usize;
+                // On rumble carts bit 3 drives the motor instead of selecting a RAM bank
+                if self.has_rumble {
+                    self.rumble = value & 0x08 != 0;
+                    self.active_ram_bank = (value & 0x07) as usize;
                 } else {
-                    todo!("implement rtc registers")
+                    self.active_ram_bank = (value & 0x0F) as usize;
                 }
-            },
-            0x6000..=0x7FFF => {
-                todo!("latch rtc register")
-            },
-            0xA000..=0xBFFF => self.ram[self.active_ram_bank * 16384 + (addr - 0xA000) as usize] = value,
-            _ => panic!("Tried to write invalid address on MBC3 cartridge: {}", addr)
+            }
+            0x6000..=0x7FFF => (),
+            0xA000..=0xBFFF if self.ram_active && !self.ram.is_empty() => {
+                let ram_addr = self.ram_addr(addr);
+                self.ram[ram_addr] = value;
+            }
+            _ => (),
+        }
+    }
+
+    fn has_battery(&self) -> bool { self.battery }
+
+    fn save_data(&self) -> Vec<u8> { self.ram.clone() }
+
+    fn load_save_data(&mut self, data: &[u8]) { load_ram(&mut self.ram, data); }
+
+    fn rumble(&self) -> bool { self.rumble }
+
+    fn rom_bank(&self, addr: u16) -> usize {
+        if addr < 0x4000 { 0 } else { self.active_rom_bank % self.rom_banks }
+    }
+}
+
+
+impl Savestate for CartridgeType {
+    // The ROM itself isn't stored: save states are checked against the loaded ROM instead
+    fn save_state(&self, w: &mut StateWriter) {
+        match self {
+            CartridgeType::NoMBC(_) => w.write_u8(0),
+            CartridgeType::MBC1(c) => { w.write_u8(1); c.save_state(w); }
+            CartridgeType::MBC2(c) => { w.write_u8(2); c.save_state(w); }
+            CartridgeType::MBC3(c) => { w.write_u8(3); c.save_state(w); }
+            CartridgeType::MBC5(c) => { w.write_u8(5); c.save_state(w); }
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        match (r.read_u8()?, self) {
+            (0, CartridgeType::NoMBC(_)) => Ok(()),
+            (1, CartridgeType::MBC1(c)) => c.load_state(r),
+            (2, CartridgeType::MBC2(c)) => c.load_state(r),
+            (3, CartridgeType::MBC3(c)) => c.load_state(r),
+            (5, CartridgeType::MBC5(c)) => c.load_state(r),
+            _ => Err(SavestateError::InvalidFormat),
+        }
+    }
+}
+
+impl Savestate for MBC1 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_slice(&self.ram);
+        w.write_u8(self.bank1);
+        w.write_u8(self.bank2);
+        w.write_bool(self.ram_active);
+        w.write_bool(self.banking_mode);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_slice_into(&mut self.ram)?;
+        self.bank1 = r.read_u8()?;
+        self.bank2 = r.read_u8()?;
+        self.ram_active = r.read_bool()?;
+        self.banking_mode = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for MBC2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_slice(&self.ram);
+        w.write_u16(self.active_rom_bank as u16);
+        w.write_bool(self.ram_active);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_slice_into(&mut self.ram)?;
+        self.active_rom_bank = r.read_u16()? as usize;
+        self.ram_active = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for RtcRegisters {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&[self.seconds, self.minutes, self.hours, self.days_low, self.days_high]);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        let mut regs = [0; 5];
+        r.read_bytes(&mut regs)?;
+        let [seconds, minutes, hours, days_low, days_high] = regs;
+        // Only the bits that exist in each register can be set (see write)
+        if seconds & !0x3F != 0 || minutes & !0x3F != 0 || hours & !0x1F != 0 || days_high & !0xC1 != 0 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        *self = RtcRegisters { seconds, minutes, hours, days_low, days_high };
+        Ok(())
+    }
+}
+
+impl Savestate for Rtc {
+    // Unlike the save file footer, this is a snapshot of emulated time: no real time is caught up on load
+    fn save_state(&self, w: &mut StateWriter) {
+        self.regs.save_state(w);
+        self.latched.save_state(w);
+        w.write_u32(self.cycles);
+        w.write_bool(self.latch_armed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.regs.load_state(r)?;
+        self.latched.load_state(r)?;
+        self.cycles = r.read_u32()?;
+        if self.cycles >= RTC_MCYCLES_PER_SECOND {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.latch_armed = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Savestate for MBC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_slice(&self.ram);
+        w.write_u16(self.active_rom_bank as u16);
+        w.write_u8(self.ram_bank_select);
+        w.write_bool(self.ram_active);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_slice_into(&mut self.ram)?;
+        self.active_rom_bank = r.read_u16()? as usize;
+        self.ram_bank_select = r.read_u8()?;
+        self.ram_active = r.read_bool()?;
+        // Whether there is a timer depends on the cartridge type, which has already been checked
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Savestate for MBC5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_slice(&self.ram);
+        w.write_u16(self.active_rom_bank as u16);
+        w.write_u8(self.active_ram_bank as u8);
+        w.write_bool(self.ram_active);
+        w.write_bool(self.rumble);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_slice_into(&mut self.ram)?;
+        self.active_rom_bank = r.read_u16()? as usize;
+        self.active_ram_bank = r.read_u8()? as usize;
+        self.ram_active = r.read_bool()?;
+        self.rumble = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtc_ticking_does_not_dirty_the_save() {
+        let mut cart = MBC3::new(&[0; 0x8000], 0x2000, true, true);
+        let key = cart.save_data_key();
+        for _ in 0..3 * RTC_MCYCLES_PER_SECOND / 255 {
+            cart.tick(255);
+        }
+        assert_eq!(cart.save_data_key(), key);
+
+        // Setting the clock does
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x08);
+        cart.write(0xA000, 30);
+        assert_ne!(cart.save_data_key(), key);
+    }
+
+    #[test]
+    fn corrupt_rtc_state_is_rejected() {
+        let mut w = StateWriter::new();
+        Rtc::new().save_state(&mut w);
+        let data = w.into_bytes();
+        assert!(Rtc::new().load_state(&mut StateReader::new(&data)).is_ok());
+
+        // Seconds with bits the register doesn't have, and a whole second left uncounted after both sets of registers
+        for (offset, bytes) in [(0, &[0xFF][..]), (10, &RTC_MCYCLES_PER_SECOND.to_le_bytes()[..])] {
+            let mut data = data.clone();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            assert!(matches!(Rtc::new().load_state(&mut StateReader::new(&data)), Err(SavestateError::InvalidFormat)));
         }
     }
 }
@@ -1,4 +1,9 @@
+use log::warn;
+
+use crate::error::{EmulatorError, ErrorKind};
+use crate::interrupts::Interrupt;
 use crate::mmu::Mmu;
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
 
 #[derive(Clone, Copy)]
 enum Flags {
@@ -14,6 +19,7 @@ impl Flags {
     }
 }
 
+#[derive(Clone)]
 pub struct CpuRegisters {
     pub a: u8,
     pub flags: u8,
@@ -91,6 +97,27 @@ impl CpuRegisters {
     }
 }
 
+impl Default for CpuRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for CpuRegisters {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&[self.a, self.flags, self.b, self.c, self.d, self.e, self.h, self.l]);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        let mut regs = [0; 8];
+        r.read_bytes(&mut regs)?;
+        let [a, flags, b, c, d, e, h, l] = regs;
+        *self = CpuRegisters { a, flags, b, c, d, e, h, l };
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     pub regs: CpuRegisters,
 
@@ -98,8 +125,20 @@ pub struct Cpu {
     pub sp: u16,
     pub pc: u16,
     pub ime: bool,
+    // EI only sets IME after the instruction following it
+    pub ime_pending: bool,
 
     pub halted: bool,
+    // Set when HALT is executed with IME off and an interrupt already pending: the CPU doesn't halt,
+    // but fails to increment PC after reading the next opcode, so that byte is read twice
+    pub halt_bug: bool,
+    // Low-power mode entered by STOP, left when a joypad line goes low
+    pub stopped: bool,
+    // Set by executing one of the illegal opcodes: the CPU hangs until power-off, while the rest of the system keeps running
+    pub locked: bool,
+
+    // M-cycles the current instruction has spent so far
+    cycles: u8,
 }
 
 impl Cpu {
@@ -110,53 +149,92 @@ impl Cpu {
             pc: 0x0100,
             sp: 0xFFFE,
             ime: false,
+            ime_pending: false,
 
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+
+            cycles: 0,
+        }
+    }
+
+    pub fn log_string(&self, mmu: &Mmu) -> String {
+        // Formats the CPU state in the common reference log layout (as used by Gameboy Doctor)
+        format!("A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})",
+            self.regs.a, self.regs.flags, self.regs.b, self.regs.c, self.regs.d, self.regs.e, self.regs.h, self.regs.l, self.sp, self.pc,
+            mmu.read(self.pc), mmu.read(self.pc.wrapping_add(1)), mmu.read(self.pc.wrapping_add(2)), mmu.read(self.pc.wrapping_add(3)))
+    }
+
+    pub fn execute(&mut self, mmu: &mut Mmu) -> Result<u8, EmulatorError> {
+        // Every memory access advances the rest of the system by an m-cycle as it happens.
+        // Whatever is left of the instruction's length afterwards is spent on internal operations.
+        self.cycles = 0;
+        let cycles = self.step(mmu)?;
+        while self.cycles < cycles {
+            self.internal(mmu);
         }
+        Ok(self.cycles)
     }
 
-    pub fn execute(&mut self, mmu: &mut Mmu) -> u8 {
+    fn step(&mut self, mmu: &mut Mmu) -> Result<u8, EmulatorError> {
+        // A locked up CPU doesn't even respond to interrupts, it just lets time pass
+        if self.locked {
+            return Ok(1);
+        }
+
         // Before executing an instruction, first check to see if we have an interrupt ready.
         // But, only when the IME flag is set.
-        let (interrupt_number, interrupt_waiting) = self.check_interrupts(mmu);
-        if interrupt_waiting {
+        if let Some(interrupt) = mmu.interrupts().pending() {
             self.halted = false;
             if self.ime {
                 // If an interrupt is serviced, it takes 5 m-cycles.
-                self.service_interrupt(mmu, interrupt_number);
-                return 5;
+                self.service_interrupt(mmu, interrupt);
+                return Ok(5);
             }
         }
 
         // If the CPU is in a halted state and we didn't just service an interrupt, perform a NOP
         if self.halted {
-            return 1;
+            return Ok(1);
+        }
+
+        // An EI in the previous instruction takes effect now, so interrupts are serviced after this instruction
+        if self.ime_pending {
+            self.ime_pending = false;
+            self.ime = true;
         }
 
         // Returns the number of m-cycles the opcode took
+        let opcode_pc = self.pc;
         let opcode: u8 = self.read_u8(mmu);
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = opcode_pc;
+        }
 
-        match opcode {
+        let cycles = match opcode {
             0x00 => { 1 }
             0x01 => { let v = self.read_u16(mmu); self.regs.set_bc(v); 3 }
-            0x02 => { mmu.write(self.regs.bc(), self.regs.a); 2 }
+            0x02 => { self.write(mmu, self.regs.bc(), self.regs.a); 2 }
             0x03 => { self.regs.set_bc(self.regs.bc().wrapping_add(1)); 2 }
             0x04 => { self.regs.b = self.increment(self.regs.b); 1 }
             0x05 => { self.regs.b = self.decrement(self.regs.b); 1 }
             0x06 => { self.regs.b = self.read_u8(mmu); 2 }
             0x07 => { self.regs.a = self.rlc(self.regs.a); self.regs.set_flag(Flags::Z, false); 1 }
-            0x08 => { let a = self.read_u16(mmu); mmu.write_word(a, self.sp); 5 }
+            0x08 => { let a = self.read_u16(mmu); self.write(mmu, a, self.sp as u8); self.write(mmu, a.wrapping_add(1), (self.sp >> 8) as u8); 5 }
             0x09 => { let v = self.add_regs(self.regs.hl(), self.regs.bc()); self.regs.set_hl(v); 2 }
-            0x0A => { self.regs.a = mmu.read(self.regs.bc()); 2 }
+            0x0A => { self.regs.a = self.read(mmu, self.regs.bc()); 2 }
             0x0B => { self.regs.set_bc(self.regs.bc().wrapping_sub(1)); 2 }
             0x0C => { self.regs.c = self.increment(self.regs.c); 1 }
             0x0D => { self.regs.c = self.decrement(self.regs.c); 1 }
             0x0E => { self.regs.c = self.read_u8(mmu); 2 }
             0x0F => { self.regs.a = self.rrc(self.regs.a); self.regs.set_flag(Flags::Z, false); 1 }
 
-            0x10 => { todo!("stop"); 1 }
+            0x10 => { self.stop(mmu); 1 }
             0x11 => { let v = self.read_u16(mmu); self.regs.set_de(v); 3 }
-            0x12 => { mmu.write(self.regs.de(), self.regs.a); 2 }
+            0x12 => { self.write(mmu, self.regs.de(), self.regs.a); 2 }
             0x13 => { self.regs.set_de(self.regs.de().wrapping_add(1)); 2 }
             0x14 => { self.regs.d = self.increment(self.regs.d); 1 }
             0x15 => { self.regs.d = self.decrement(self.regs.d); 1 }
@@ -164,41 +242,41 @@ impl Cpu {
             0x17 => { self.regs.a = self.rl(self.regs.a); self.regs.set_flag(Flags::Z, false); 1 }
             0x18 => { self.jump_rel(mmu); 3 }
             0x19 => { let v = self.add_regs(self.regs.hl(), self.regs.de()); self.regs.set_hl(v); 2 }
-            0x1A => { self.regs.a = mmu.read(self.regs.de()); 2 }
+            0x1A => { self.regs.a = self.read(mmu, self.regs.de()); 2 }
             0x1B => { self.regs.set_de(self.regs.de().wrapping_sub(1)); 2 }
             0x1C => { self.regs.e = self.increment(self.regs.e); 1 }
             0x1D => { self.regs.e = self.decrement(self.regs.e); 1 }
             0x1E => { self.regs.e = self.read_u8(mmu); 2 }
             0x1F => { self.regs.a = self.rr(self.regs.a); self.regs.set_flag(Flags::Z, false); 1 }
 
-            0x20 => { if !self.regs.get_flag(Flags::Z) { self.jump_rel(mmu); 3 } else { self.pc += 1; 2 } }
+            0x20 => { if !self.regs.get_flag(Flags::Z) { self.jump_rel(mmu); 3 } else { self.pc = self.pc.wrapping_add(1); 2 } }
             0x21 => { let v = self.read_u16(mmu); self.regs.set_hl(v); 3 }
-            0x22 => { mmu.write(self.regs.hli(), self.regs.a); 2 }
+            0x22 => { let a = self.regs.hli(); self.write(mmu, a, self.regs.a); 2 }
             0x23 => { self.regs.set_hl(self.regs.hl().wrapping_add(1)); 2 }
             0x24 => { self.regs.h = self.increment(self.regs.h); 1 }
             0x25 => { self.regs.h = self.decrement(self.regs.h); 1 }
             0x26 => { self.regs.h = self.read_u8(mmu); 2 }
             0x27 => { self.daa(); 1 },
-            0x28 => { if self.regs.get_flag(Flags::Z) { self.jump_rel(mmu); 3 } else { self.pc += 1; 2 } }
+            0x28 => { if self.regs.get_flag(Flags::Z) { self.jump_rel(mmu); 3 } else { self.pc = self.pc.wrapping_add(1); 2 } }
             0x29 => { let v = self.add_regs(self.regs.hl(), self.regs.hl()); self.regs.set_hl(v); 2 }
-            0x2A => { self.regs.a = mmu.read(self.regs.hli()); 2 }
+            0x2A => { let a = self.regs.hli(); self.regs.a = self.read(mmu, a); 2 }
             0x2B => { self.regs.set_hl(self.regs.hl().wrapping_sub(1)); 2 }
             0x2C => { self.regs.l = self.increment(self.regs.l); 1 }
             0x2D => { self.regs.l = self.decrement(self.regs.l); 1 }
             0x2E => { self.regs.l = self.read_u8(mmu); 2 }
             0x2F => { self.regs.a = !self.regs.a; self.regs.set_flag(Flags::N, true); self.regs.set_flag(Flags::H, true); 1 }
 
-            0x30 => { if !self.regs.get_flag(Flags::C) { self.jump_rel(mmu); 3 } else { self.pc += 1; 2 } }
+            0x30 => { if !self.regs.get_flag(Flags::C) { self.jump_rel(mmu); 3 } else { self.pc = self.pc.wrapping_add(1); 2 } }
             0x31 => { let v = self.read_u16(mmu); self.sp = v; 3 }
-            0x32 => { mmu.write(self.regs.hld(), self.regs.a); 2 }
+            0x32 => { let a = self.regs.hld(); self.write(mmu, a, self.regs.a); 2 }
             0x33 => { self.sp = self.sp.wrapping_add(1); 2 }
-            0x34 => { let v = self.increment(mmu.read(self.regs.hl())); mmu.write(self.regs.hl(), v); 3 }
-            0x35 => { let v = self.decrement(mmu.read(self.regs.hl())); mmu.write(self.regs.hl(), v); 3 }
-            0x36 => { mmu.write(self.regs.hl(), self.read_u8(mmu)); 3 }            
+            0x34 => { let v = self.read(mmu, self.regs.hl()); let v = self.increment(v); self.write(mmu, self.regs.hl(), v); 3 }
+            0x35 => { let v = self.read(mmu, self.regs.hl()); let v = self.decrement(v); self.write(mmu, self.regs.hl(), v); 3 }
+            0x36 => { let v = self.read_u8(mmu); self.write(mmu, self.regs.hl(), v); 3 }            
             0x37 => { self.regs.set_flag(Flags::H, false); self.regs.set_flag(Flags::N, false); self.regs.set_flag(Flags::C, true); 1 }
-            0x38 => { if self.regs.get_flag(Flags::C) { self.jump_rel(mmu); 3 } else { self.pc += 1; 2 } }
+            0x38 => { if self.regs.get_flag(Flags::C) { self.jump_rel(mmu); 3 } else { self.pc = self.pc.wrapping_add(1); 2 } }
             0x39 => { let v = self.add_regs(self.regs.hl(), self.sp); self.regs.set_hl(v); 2 }
-            0x3A => { self.regs.a = mmu.read(self.regs.hld()); 2 }
+            0x3A => { let a = self.regs.hld(); self.regs.a = self.read(mmu, a); 2 }
             0x3B => { self.sp = self.sp.wrapping_sub(1); 2 }
             0x3C => { self.regs.a = self.increment(self.regs.a); 1 }
             0x3D => { self.regs.a = self.decrement(self.regs.a); 1 }
@@ -206,7 +284,7 @@ impl Cpu {
             0x3F => { self.regs.set_flag(Flags::H, false); self.regs.set_flag(Flags::N, false); self.regs.set_flag(Flags::C, !self.regs.get_flag(Flags::C)); 1 }
 
             // HALT
-            0x76 => { self.halt(); 1 }
+            0x76 => { self.halt(mmu); 1 }
 
             // LD dest, source
             0x40..=0x7F => {
@@ -217,7 +295,8 @@ impl Cpu {
                 cycles += if source_code == 0x06 {1} else {0};
                 cycles += if dest_code == 0x06 {1} else {0};
 
-                self.set_reg_from_code(mmu, dest_code, self.code_to_reg(mmu, source_code));
+                let v = self.code_to_reg(mmu, source_code);
+                self.set_reg_from_code(mmu, dest_code, v);
 
                 cycles
             }
@@ -225,7 +304,8 @@ impl Cpu {
             // ADD A, r8
             0x80..=0x8F => {
                 let carry = opcode & 0x08 == 0x08;
-                self.regs.a = self.add(self.code_to_reg(mmu, opcode), carry);
+                let r = self.code_to_reg(mmu, opcode);
+                self.regs.a = self.add(r, carry);
 
                 // Performing this operation with a memory access takes an extra m-cycle
                 if opcode & 0x07 == 0x06 {2} else {1}
@@ -234,35 +314,40 @@ impl Cpu {
             // SUB A, r8
             0x90..=0x9F => {
                 let carry = opcode & 0x08 == 0x08;
-                self.regs.a = self.sub(self.code_to_reg(mmu, opcode), carry);
+                let r = self.code_to_reg(mmu, opcode);
+                self.regs.a = self.sub(r, carry);
                 if opcode & 0x07 == 0x06 {2} else {1}
             }
             
             // AND A, r8
             0xA0..=0xA7 => {
-                self.regs.a = self.and(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                self.regs.a = self.and(r);
                 if opcode & 0x07 == 0x06 {2} else {1}
             }
 
             // XOR A, r8
             0xA8..=0xAF => {
-                self.regs.a = self.xor(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                self.regs.a = self.xor(r);
                 if opcode & 0x07 == 0x06 {2} else {1}
             }
 
             // OR A, r8
             0xB0..=0xB7 => {
-                self.regs.a = self.or(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                self.regs.a = self.or(r);
                 if opcode & 0x07 == 0x06 {2} else {1}
             }
 
             // CP r8
             0xB8..=0xBF => {
-                self.cp(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                self.cp(r);
                 if opcode & 0x07 == 0x06 {2} else {1}
             }
 
-            0xC0 => { if !self.regs.get_flag(Flags::Z) { self.ret(mmu); 5 } else { 2 } }
+            0xC0 => { self.ret_cond(mmu, !self.regs.get_flag(Flags::Z)) }
             0xC1 => { let v = self.pop_stack(mmu); self.regs.set_bc(v); 3 }
             0xC2 => { self.jump_imm(mmu, !self.regs.get_flag(Flags::Z)) }
             0xC3 => { self.jump_imm(mmu, true) }
@@ -270,7 +355,7 @@ impl Cpu {
             0xC5 => { self.push_stack(mmu, self.regs.bc()); 4 }
             0xC6 => { let v = self.read_u8(mmu); self.regs.a = self.add(v, false); 2 }
             0xC7 => { self.rst(mmu, 0x00); 4 }
-            0xC8 => { if self.regs.get_flag(Flags::Z) { self.ret(mmu); 5 } else { 2 } }
+            0xC8 => { self.ret_cond(mmu, self.regs.get_flag(Flags::Z)) }
             0xC9 => { self.ret(mmu); 4 }
             0xCA => { self.jump_imm(mmu, self.regs.get_flag(Flags::Z)) }
             0xCB => { self.execute_cb(mmu) }
@@ -279,7 +364,7 @@ impl Cpu {
             0xCE => { let v = self.read_u8(mmu); self.regs.a = self.add(v, true); 2 }
             0xCF => { self.rst(mmu, 0x08); 4 }
             
-            0xD0 => { if !self.regs.get_flag(Flags::C) { self.ret(mmu); 5 } else { 2 } }
+            0xD0 => { self.ret_cond(mmu, !self.regs.get_flag(Flags::C)) }
             0xD1 => { let v = self.pop_stack(mmu); self.regs.set_de(v); 3 }
             0xD2 => { self.jump_imm(mmu, !self.regs.get_flag(Flags::C)) }
 
@@ -287,7 +372,7 @@ impl Cpu {
             0xD5 => { self.push_stack(mmu, self.regs.de()); 4 }
             0xD6 => { let v = self.read_u8(mmu); self.regs.a = self.sub(v, false); 2 }
             0xD7 => { self.rst(mmu, 0x10); 4 }
-            0xD8 => { if self.regs.get_flag(Flags::C) { self.ret(mmu); 5 } else { 2 } }
+            0xD8 => { self.ret_cond(mmu, self.regs.get_flag(Flags::C)) }
             0xD9 => { self.ret(mmu); self.enable_interrupts(); 4 }
             0xDA => { self.jump_imm(mmu, self.regs.get_flag(Flags::C)) }
 
@@ -296,23 +381,23 @@ impl Cpu {
             0xDE => { let v = self.read_u8(mmu); self.regs.a = self.sub(v, true); 2 }
             0xDF => { self.rst(mmu, 0x18); 4 }
 
-            0xE0 => { mmu.write(0xFF00 + self.read_u8(mmu) as u16, self.regs.a); 3 }
+            0xE0 => { let a = 0xFF00 + self.read_u8(mmu) as u16; self.write(mmu, a, self.regs.a); 3 }
             0xE1 => { let v = self.pop_stack(mmu); self.regs.set_hl(v); 3 }
-            0xE2 => { mmu.write(0xFF00 + self.regs.c as u16, self.regs.a); 2 }
+            0xE2 => { self.write(mmu, 0xFF00 + self.regs.c as u16, self.regs.a); 2 }
 
             0xE5 => { self.push_stack(mmu, self.regs.hl()); 4 }
             0xE6 => { let v = self.read_u8(mmu); self.regs.a = self.and(v); 2 }
             0xE7 => { self.rst(mmu, 0x20); 4 }
             0xE8 => { self.sp = self.add_imm(mmu, self.sp); 4 }
             0xE9 => { self.pc = self.regs.hl(); 1 }
-            0xEA => { let a = self.read_u16(mmu); mmu.write(a, self.regs.a); 4 }
+            0xEA => { let a = self.read_u16(mmu); self.write(mmu, a, self.regs.a); 4 }
 
             0xEE => { let v = self.read_u8(mmu); self.regs.a = self.xor(v); 2 }
             0xEF => { self.rst(mmu, 0x28); 4 }
             
-            0xF0 => { self.regs.a = mmu.read(0xFF00 + (self.read_u8(mmu) as u16)); 3 }
+            0xF0 => { let a = 0xFF00 + self.read_u8(mmu) as u16; self.regs.a = self.read(mmu, a); 3 }
             0xF1 => { let v = self.pop_stack(mmu) & 0xFFF0; self.regs.set_af(v); 3 }
-            0xF2 => { self.regs.a = mmu.read(0xFF00 + self.regs.c as u16); 2 }
+            0xF2 => { self.regs.a = self.read(mmu, 0xFF00 + self.regs.c as u16); 2 }
             0xF3 => { self.disable_interrupts(); 1 }
 
             0xF5 => { self.push_stack(mmu, self.regs.af()); 4 }
@@ -320,14 +405,26 @@ impl Cpu {
             0xF7 => { self.rst(mmu, 0x30); 4 }
             0xF8 => { let v = self.add_imm(mmu, self.sp); self.regs.set_hl(v); 3 }
             0xF9 => { self.sp = self.regs.hl(); 2 }
-            0xFA => { let a = self.read_u16(mmu); self.regs.a = mmu.read(a); 4 }
-            0xFB => { self.enable_interrupts(); 1 }
+            0xFA => { let a = self.read_u16(mmu); self.regs.a = self.read(mmu, a); 4 }
+            0xFB => { self.ime_pending = true; 1 }
 
             0xFE => { let v = self.read_u8(mmu); self.cp(v); 2 }
             0xFF => { self.rst(mmu, 0x38); 4 }
 
-            _ => { panic!("invalid opcode {:02X}", opcode)}
-        }
+            // 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD
+            _ => {
+                self.lock(opcode, opcode_pc);
+                return Err(EmulatorError::new(ErrorKind::IllegalOpcode, opcode_pc, opcode, mmu));
+            }
+        };
+        Ok(cycles)
+    }
+
+    fn lock(&mut self, opcode: u8, pc: u16) {
+        // Leave PC on the illegal opcode, so it's obvious what happened
+        self.pc = pc;
+        self.locked = true;
+        warn!("CPU locked up: illegal opcode {:02X} at {:04X}", opcode, pc);
     }
 
     fn execute_cb(&mut self, mmu: &mut Mmu) -> u8 {
@@ -337,56 +434,64 @@ impl Cpu {
         match opcode {
             // RLC
             0x00..=0x07 => { 
-                let v = self.rlc(self.code_to_reg(mmu, opcode)); 
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.rlc(r); 
                 self.set_reg_from_code(mmu, opcode, v); 
                 if mem_access {4} else {2}
             }
 
             // RRC
             0x08..=0x0F => { 
-                let v = self.rrc(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.rrc(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
 
             // RL
             0x10..=0x17 => {
-                let v = self.rl(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.rl(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
 
             // RR
             0x18..=0x1F => {
-                let v = self.rr(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.rr(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
 
             // SLA
             0x20..=0x27 => {
-                let v = self.sla(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.sla(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
 
             // SRA
             0x28..=0x2F => {
-                let v = self.sra(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.sra(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
 
             // SWAP
             0x30..=0x37 => {
-                let v = self.swap(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.swap(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
 
             // SRL
             0x38..=0x3F => { 
-                let v = self.srl(self.code_to_reg(mmu, opcode));
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.srl(r);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
@@ -395,7 +500,8 @@ impl Cpu {
             0x40..=0x7F => {
                 // Decode bit to check
                 let b = (opcode >> 3) & 0x07;
-                self.bit(self.code_to_reg(mmu, opcode), b);
+                let r = self.code_to_reg(mmu, opcode);
+                self.bit(r, b);
                 if mem_access{3} else {2}
             }
 
@@ -403,7 +509,8 @@ impl Cpu {
             0x80..=0xBF => {
                 // Decode bit to reset
                 let b = (opcode >> 3) & 0x07;
-                let v = self.res(self.code_to_reg(mmu, opcode), b);
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.res(r, b);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
@@ -412,14 +519,15 @@ impl Cpu {
             0xC0..=0xFF => {
                 // Decode bit to set
                 let b = (opcode >> 3) & 0x07;
-                let v = self.set(self.code_to_reg(mmu, opcode), b);
+                let r = self.code_to_reg(mmu, opcode);
+                let v = self.set(r, b);
                 self.set_reg_from_code(mmu, opcode, v);
                 if mem_access {4} else {2}
             }
         }
     }
 
-    fn code_to_reg(&self, mmu: &Mmu, opcode: u8) -> u8 {
+    fn code_to_reg(&mut self, mmu: &mut Mmu, opcode: u8) -> u8 {
         match opcode & 0x07 {
             0x00 => self.regs.b,
             0x01 => self.regs.c,
@@ -427,7 +535,7 @@ impl Cpu {
             0x03 => self.regs.e,
             0x04 => self.regs.h,
             0x05 => self.regs.l,
-            0x06 => mmu.read(self.regs.hl()),
+            0x06 => self.read(mmu, self.regs.hl()),
             0x07 => self.regs.a,
             _ => panic!("invalid register code")
         }
@@ -441,24 +549,41 @@ impl Cpu {
             0x03 => self.regs.e = v,
             0x04 => self.regs.h = v,
             0x05 => self.regs.l = v,
-            0x06 => mmu.write(self.regs.hl(), v),
+            0x06 => self.write(mmu, self.regs.hl(), v),
             0x07 => self.regs.a = v,
             _ => panic!("invalid register code")
         }
     }
 
-    fn read_u8(&mut self, mmu: &Mmu) -> u8 {
+    fn read(&mut self, mmu: &mut Mmu, addr: u16) -> u8 {
+        // Memory accesses happen at the end of their m-cycle
+        self.internal(mmu);
+        mmu.read(addr)
+    }
+
+    fn write(&mut self, mmu: &mut Mmu, addr: u16, v: u8) {
+        self.internal(mmu);
+        mmu.write(addr, v);
+    }
+
+    fn internal(&mut self, mmu: &mut Mmu) {
+        // An m-cycle without a memory access
+        self.cycles += 1;
+        mmu.tick(1);
+    }
+
+    fn read_u8(&mut self, mmu: &mut Mmu) -> u8 {
         // Read a u8 immediate and increment PC
-        let v = mmu.read(self.pc);
-        self.pc += 1;
+        let v = self.read(mmu, self.pc);
+        self.pc = self.pc.wrapping_add(1);
         v
     }
 
-    fn read_u16(&mut self, mmu: &Mmu) -> u16 {
+    fn read_u16(&mut self, mmu: &mut Mmu) -> u16 {
         // Read a u16 immediate and increment PC by 2
-        let v = mmu.read_word(self.pc);
-        self.pc += 2;
-        v
+        let lo = self.read_u8(mmu) as u16;
+        let hi = self.read_u8(mmu) as u16;
+        (hi << 8) | lo
     }
 
     fn increment(&mut self, a: u8) -> u8 {
@@ -627,7 +752,7 @@ impl Cpu {
 
     fn swap(&mut self, v: u8) -> u8 {
         // Swap upper and lower nibbles of byte
-        let result = (v << 4) | (v >> 4);
+        let result = v.rotate_left(4);
 
         self.regs.set_flag(Flags::Z, result == 0);
         self.regs.set_flag(Flags::N, false);
@@ -678,7 +803,7 @@ impl Cpu {
         res
     }
 
-    fn add_imm(&mut self, mmu: &Mmu, lhs: u16) -> u16 {
+    fn add_imm(&mut self, mmu: &mut Mmu, lhs: u16) -> u16 {
         // Add an immediate i8 to a provided u16. Sets flags
         let rhs = self.read_u8(mmu) as i8 as i16 as u16;
         let res = lhs.wrapping_add(rhs);
@@ -689,7 +814,7 @@ impl Cpu {
         res
     }
 
-    fn jump_imm(&mut self, mmu: &Mmu, condition: bool) -> u8 {
+    fn jump_imm(&mut self, mmu: &mut Mmu, condition: bool) -> u8 {
         // Jump to an immediate u16 address
         let addr = self.read_u16(mmu);
         if condition {
@@ -700,23 +825,28 @@ impl Cpu {
         }
     }
 
-    fn jump_rel(&mut self, mmu: &Mmu) {
+    fn jump_rel(&mut self, mmu: &mut Mmu) {
         // Jump relative to the current PC by an immediate i8 value
         let r = self.read_u8(mmu) as i8;
         self.pc = ((self.pc as u32 as i32) + (r as i32)) as u16;
     }
 
     fn push_stack(&mut self, mmu: &mut Mmu, value: u16) {
-        // Decrement stack pointer and write value
-        self.sp -= 2;
-        mmu.write_word(self.sp, value);
+        // Decrement stack pointer and write value, high byte first. SP is decremented during an internal cycle
+        self.internal(mmu);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mmu, self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(mmu, self.sp, value as u8);
     }
 
-    fn pop_stack(&mut self, mmu: &Mmu) -> u16 {
+    fn pop_stack(&mut self, mmu: &mut Mmu) -> u16 {
         // Pop a value off the stack, then increment stack pointer 2
-        let v = mmu.read_word(self.sp);
-        self.sp += 2;
-        v
+        let lo = self.read(mmu, self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read(mmu, self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (hi << 8) | lo
     }
 
     fn call(&mut self, mmu: &mut Mmu, condition: bool) -> u8 {
@@ -732,11 +862,22 @@ impl Cpu {
         }
     }
 
-    fn ret(&mut self, mmu: &Mmu) {
+    fn ret(&mut self, mmu: &mut Mmu) {
         // Return from the current function by setting the PC to the popped stack value
         self.pc = self.pop_stack(mmu)
     }
 
+    fn ret_cond(&mut self, mmu: &mut Mmu, condition: bool) -> u8 {
+        // The condition is checked during an internal cycle, before anything is popped
+        self.internal(mmu);
+        if condition {
+            self.ret(mmu);
+            5
+        } else {
+            2
+        }
+    }
+
     fn rst(&mut self, mmu: &mut Mmu, addr: u16) {
         // Push the current address to the stack and reset to address
         self.push_stack(mmu, self.pc);
@@ -782,31 +923,255 @@ impl Cpu {
 
     fn disable_interrupts(&mut self) {
         self.ime = false;
+        self.ime_pending = false;
     }
     
-    fn halt(&mut self) {
-        self.halted = true;
+    fn stop(&mut self, mmu: &mut Mmu) {
+        // STOP is followed by a padding byte, which is skipped
+        self.read_u8(mmu);
+        // Entering (and leaving) low-power mode is up to the motherboard, as it stops the whole system clock.
+        // That's also where a CGB speed switch would happen, as it's triggered by STOP as well.
+        self.stopped = true;
     }
 
-    fn check_interrupts(&mut self, mmu: &Mmu) -> (u8, bool) {
-        // Compare the Interrupt Enable register (0xFFFF) to the Interrupt Flag register (0xFF0F)
-        let if_reg = mmu.read(0xFF0F);
-        let interrupts = mmu.read(0xFFFF) & if_reg;
-
-        match interrupts {
-            0 => (0, false),
-            _ => (interrupts.trailing_zeros() as u8, true)
-        }    
+    fn halt(&mut self, mmu: &mut Mmu) {
+        if !self.ime && mmu.interrupts().pending().is_some() {
+            // The HALT bug: instead of halting, the next opcode byte is read twice
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
     }
 
-    fn service_interrupt(&mut self, mmu: &mut Mmu, interrupt_number: u8) {
-        // Do work for interrupt based on interrupt number
-
-        // Disable IME and IF bit for this interrupt
+    fn service_interrupt(&mut self, mmu: &mut Mmu, interrupt: Interrupt) {
+        // Disable IME and acknowledge the interrupt, clearing its IF bit
         self.ime = false;
-        mmu.write(0xFF0F, mmu.read(0xFF0F) & (!(1 << interrupt_number)));
+        mmu.interrupts_mut().acknowledge(interrupt);
+
+        // Two cycles pass before the push starts
+        self.internal(mmu);
 
         // Push the current PC onto the stack and set PC to interrupt vector
-        self.rst(mmu, 0x40 + (0x08 * interrupt_number) as u16);
+        self.rst(mmu, interrupt.vector());
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.regs.save_state(w);
+        w.write_u16(self.sp);
+        w.write_u16(self.pc);
+        w.write_bool(self.ime);
+        w.write_bool(self.ime_pending);
+        w.write_bool(self.halted);
+        w.write_bool(self.halt_bug);
+        w.write_bool(self.stopped);
+        w.write_bool(self.locked);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.regs.load_state(r)?;
+        self.sp = r.read_u16()?;
+        self.pc = r.read_u16()?;
+        self.ime = r.read_bool()?;
+        self.ime_pending = r.read_bool()?;
+        self.halted = r.read_bool()?;
+        self.halt_bug = r.read_bool()?;
+        self.stopped = r.read_bool()?;
+        self.locked = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::load_cartridge;
+    use crate::header::CartridgeHeader;
+    use crate::memory::{DMGMemory, MemoryType};
+
+    // A CPU with a ROM-only cartridge that has the given program at 0x0100
+    fn setup(program: &[u8]) -> (Cpu, Mmu) {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        let mmu = Mmu::new(MemoryType::DMGMemory(DMGMemory::new()), load_cartridge(&rom, &header).unwrap());
+        (Cpu::new(), mmu)
+    }
+
+    fn request_vblank(mmu: &mut Mmu) {
+        mmu.write(0xFFFF, 0x01);
+        mmu.write(0xFF0F, 0x01);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // EI; NOP; NOP
+        let (mut cpu, mut mmu) = setup(&[0xFB, 0x00, 0x00]);
+        request_vblank(&mut mmu);
+
+        cpu.execute(&mut mmu).unwrap();
+        assert!(!cpu.ime);
+
+        // The instruction after EI still runs before the interrupt is serviced
+        cpu.execute(&mut mmu).unwrap();
+        assert_eq!(cpu.pc, 0x0102);
+        assert!(cpu.ime);
+
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 5);
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(mmu.read_word(cpu.sp), 0x0102);
+    }
+
+    #[test]
+    fn di_right_after_ei_keeps_interrupts_disabled() {
+        // EI; DI; NOP
+        let (mut cpu, mut mmu) = setup(&[0xFB, 0xF3, 0x00]);
+        request_vblank(&mut mmu);
+
+        for _ in 0..3 {
+            cpu.execute(&mut mmu).unwrap();
+        }
+        assert!(!cpu.ime);
+        assert_eq!(cpu.pc, 0x0103);
+    }
+
+    #[test]
+    fn reti_enables_interrupts_immediately() {
+        // RETI, returning to 0x0200
+        let (mut cpu, mut mmu) = setup(&[0xD9]);
+        cpu.sp = 0xDFF0;
+        mmu.write_word(cpu.sp, 0x0200);
+        request_vblank(&mut mmu);
+
+        cpu.execute(&mut mmu).unwrap();
+        assert!(cpu.ime);
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 5);
+        assert_eq!(cpu.pc, 0x0040);
+    }
+
+    #[test]
+    fn halt_bug_reads_the_next_byte_twice() {
+        // HALT; INC A; NOP, with IME off and an interrupt pending
+        let (mut cpu, mut mmu) = setup(&[0x76, 0x3C, 0x00]);
+        cpu.regs.a = 0;
+        request_vblank(&mut mmu);
+
+        cpu.execute(&mut mmu).unwrap();
+        assert!(!cpu.halted);
+
+        // INC A is executed twice, as PC doesn't move past it the first time
+        cpu.execute(&mut mmu).unwrap();
+        assert_eq!(cpu.pc, 0x0101);
+        cpu.execute(&mut mmu).unwrap();
+        assert_eq!(cpu.pc, 0x0102);
+        assert_eq!(cpu.regs.a, 2);
+    }
+
+    #[test]
+    fn halt_without_pending_interrupt_waits_for_one() {
+        // HALT; INC A, with IME off
+        let (mut cpu, mut mmu) = setup(&[0x76, 0x3C]);
+        cpu.regs.a = 0;
+        mmu.write(0xFFFF, 0x01);
+
+        cpu.execute(&mut mmu).unwrap();
+        assert!(cpu.halted);
+        cpu.execute(&mut mmu).unwrap();
+        assert_eq!(cpu.pc, 0x0101);
+
+        // With IME off the interrupt isn't serviced, but it still ends the HALT, without the bug
+        mmu.write(0xFF0F, 0x01);
+        cpu.execute(&mut mmu).unwrap();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x0102);
+        assert_eq!(cpu.regs.a, 1);
+    }
+
+    #[test]
+    fn halt_with_ime_services_the_interrupt() {
+        // EI; HALT; NOP
+        let (mut cpu, mut mmu) = setup(&[0xFB, 0x76, 0x00]);
+        mmu.write(0xFFFF, 0x01);
+
+        cpu.execute(&mut mmu).unwrap();
+        cpu.execute(&mut mmu).unwrap();
+        assert!(cpu.halted);
+
+        mmu.write(0xFF0F, 0x01);
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 5);
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(mmu.read_word(cpu.sp), 0x0102);
+    }
+
+    #[test]
+    fn memory_accesses_see_the_cycles_before_them() {
+        // LDH (DIV),A; 61 NOPs; LDH A,(DIV). DIV ticks over 64 m-cycles after the reset, which is exactly when the read happens.
+        let mut program = vec![0xE0, 0x04];
+        program.extend_from_slice(&[0x00; 61]);
+        program.extend_from_slice(&[0xF0, 0x04]);
+        let (mut cpu, mut mmu) = setup(&program);
+
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 3);
+        for _ in 0..61 {
+            assert_eq!(cpu.execute(&mut mmu).unwrap(), 1);
+        }
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 3);
+        assert_eq!(cpu.regs.a, 1);
+    }
+
+    #[test]
+    fn conditional_ret_pops_after_checking_the_condition() {
+        // LDH (DIV),A; 61 NOPs; RET NC with the stack on DIV. The low byte is popped in the third m-cycle, 64 m-cycles after the reset.
+        let mut program = vec![0xE0, 0x04];
+        program.extend_from_slice(&[0x00; 61]);
+        program.push(0xD0);
+        let (mut cpu, mut mmu) = setup(&program);
+
+        for _ in 0..62 {
+            cpu.execute(&mut mmu).unwrap();
+        }
+        cpu.regs.flags = 0;
+        cpu.sp = 0xFF04;
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 5);
+        assert_eq!(cpu.pc, 0x0001);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn illegal_opcode_is_reported_once_then_locks_up() {
+        let (mut cpu, mut mmu) = setup(&[0x00, 0xD3, 0x00]);
+
+        cpu.execute(&mut mmu).unwrap();
+        let error = cpu.execute(&mut mmu).unwrap_err();
+        assert_eq!(error, EmulatorError { kind: ErrorKind::IllegalOpcode, pc: 0x0101, opcode: 0xD3, rom_bank: Some(0) });
+        assert!(cpu.locked);
+
+        // Time keeps passing, but the CPU doesn't move on
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 1);
+        assert_eq!(cpu.pc, 0x0101);
+    }
+    #[test]
+    fn pc_wraps_around_at_the_end_of_the_address_space() {
+        let (mut cpu, mut mmu) = setup(&[]);
+
+        // JR Z at 0xFFFE, with its operand in IE, falls through to 0x0000
+        mmu.write(0xFFFE, 0x28);
+        cpu.pc = 0xFFFE;
+        cpu.regs.flags = 0;
+        assert_eq!(cpu.execute(&mut mmu).unwrap(), 2);
+        assert_eq!(cpu.pc, 0x0000);
+
+        // NOP in IE
+        mmu.write(0xFFFF, 0x00);
+        cpu.pc = 0xFFFF;
+        cpu.execute(&mut mmu).unwrap();
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(mmu.read_word(0xFFFF), 0x0000);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+// OAM DMA copies 160 bytes to OAM, one per m-cycle, after a start-up delay of one m-cycle.
+// While it runs, the CPU can't access OAM, nor anything on the bus the transfer reads from.
+
+const TRANSFER_LENGTH: u8 = 160;
+
+// The CPU and DMA share the external bus (cartridge and work RAM) and the video bus (VRAM)
+#[derive(PartialEq)]
+enum Bus {
+    External,
+    Video,
+}
+
+fn bus(addr: u16) -> Option<Bus> {
+    match addr {
+        0x8000..=0x9FFF => Some(Bus::Video),
+        // OAM, HRAM and the IO registers have their own connections
+        0xFE00..=0xFFFF => None,
+        _ => Some(Bus::External),
+    }
+}
+
+#[derive(Clone)]
+pub struct OamDma {
+    // Last value written to 0xFF46
+    reg: u8,
+    // A requested transfer: its source address, and the m-cycles until it starts
+    starting: Option<(u16, u8)>,
+    // The transfer in progress: its source address, and the number of bytes copied so far
+    active: Option<(u16, u8)>,
+    // The byte the transfer last read, which is what the CPU sees on a conflicting bus
+    value: u8,
+}
+
+impl OamDma {
+    pub fn new() -> Self {
+        OamDma { reg: 0xFF, starting: None, active: None, value: 0xFF }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.reg
+    }
+
+    pub fn start(&mut self, value: u8) {
+        // Writing again while a transfer is running restarts it, but the old one carries on during the start-up delay
+        self.reg = value;
+        let source = (value as u16) << 8;
+        // Sources above work RAM read from its echo instead
+        let source = if source >= 0xE000 { source - 0x2000 } else { source };
+        self.starting = Some((source, 1));
+    }
+
+    pub fn tick(&mut self) -> Option<(u16, u8)> {
+        // Advances by one m-cycle. Returns the transfer to do in this cycle: the source address, and the offset into OAM.
+        if let Some((_, TRANSFER_LENGTH)) = self.active {
+            self.active = None;
+        }
+
+        if let Some((source, delay)) = self.starting {
+            if delay > 0 {
+                self.starting = Some((source, delay - 1));
+            } else {
+                self.starting = None;
+                self.active = Some((source, 0));
+            }
+        }
+
+        let (source, offset) = self.active?;
+        self.active = Some((source, offset + 1));
+        Some((source + offset as u16, offset))
+    }
+
+    pub fn set_value(&mut self, value: u8) {
+        // The byte the current transfer read from its source
+        self.value = value;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub fn conflict(&self, addr: u16) -> Option<u8> {
+        // What the CPU sees when accessing addr during a transfer, if the transfer gets in the way
+        let (source, _) = self.active?;
+        match addr {
+            0xFE00..=0xFEFF => Some(0xFF),
+            _ if bus(addr).is_some() && bus(addr) == bus(source) => Some(self.value),
+            _ => None,
+        }
+    }
+}
+
+impl Default for OamDma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for OamDma {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.reg);
+        for transfer in [self.starting, self.active] {
+            let (source, count) = transfer.unwrap_or((0, 0));
+            w.write_bool(transfer.is_some());
+            w.write_u16(source);
+            w.write_u8(count);
+        }
+        w.write_u8(self.value);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.reg = r.read_u8()?;
+        let mut transfers = [None; 2];
+        for transfer in transfers.iter_mut() {
+            let present = r.read_bool()?;
+            let (source, count) = (r.read_u16()?, r.read_u8()?);
+            if present {
+                *transfer = Some((source, count));
+            }
+        }
+        // Only accept transfers start() could have set up: sources are page aligned, and mapped below 0xE000
+        let valid_source = |source: u16| source & 0xFF == 0 && source <= 0xDF00;
+        let invalid = matches!(transfers[0], Some((source, delay)) if !valid_source(source) || delay > 1)
+            || matches!(transfers[1], Some((source, count)) if !valid_source(source) || count > TRANSFER_LENGTH);
+        if invalid {
+            return Err(SavestateError::InvalidFormat);
+        }
+        [self.starting, self.active] = transfers;
+        self.value = r.read_u8()?;
+        Ok(())
+    }
+}
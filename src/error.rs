@@ -0,0 +1,43 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::cartridge::Cartridge;
+use crate::mmu::Mmu;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    // One of the eleven unused opcodes, which locks up the CPU
+    IllegalOpcode,
+}
+
+// An error that stopped the emulated program, along with where the CPU was when it happened.
+// The CPU is left pointing at the failing instruction. Ticking again carries on like the hardware would, with the CPU locked up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmulatorError {
+    pub kind: ErrorKind,
+    pub pc: u16,
+    pub opcode: u8,
+    // The cartridge ROM bank PC was in, if it was in ROM at all
+    pub rom_bank: Option<usize>,
+}
+
+impl EmulatorError {
+    pub fn new(kind: ErrorKind, pc: u16, opcode: u8, mmu: &Mmu) -> Self {
+        let rom_bank = if pc < 0x8000 { Some(mmu.cartridge().rom_bank(pc)) } else { None };
+        EmulatorError { kind, pc, opcode, rom_bank }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ErrorKind::IllegalOpcode => write!(f, "CPU locked up on illegal opcode {:02X}", self.opcode)?,
+        }
+        match self.rom_bank {
+            Some(bank) => write!(f, " at {:02X}:{:04X}", bank, self.pc),
+            None => write!(f, " at {:04X}", self.pc),
+        }
+    }
+}
+
+impl Error for EmulatorError {}
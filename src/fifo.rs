@@ -0,0 +1,435 @@
+use std::collections::VecDeque;
+
+use crate::lcd::{LCD_HEIGHT, LCD_WIDTH};
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+// Dot-accurate renderer for mode 3. Instead of drawing a scanline all at once, pixels are fetched into FIFOs and
+// shifted out to the LCD one dot at a time, like the hardware does. Register writes in the middle of a line
+// take effect where they would on hardware, and mode 3 takes as long as it would on hardware.
+
+// Each step of the background fetcher takes 2 dots, then it waits until it can push the tile into the empty FIFO
+#[derive(Clone, Copy, PartialEq)]
+enum FetchStep {
+    TileNumber,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    // 0 for OBP0, 1 for OBP1
+    palette: u8,
+    bg_priority: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    fetched: bool,
+}
+
+// Dots taken by the tile fetch at the start of a line, whose result is thrown away
+const STARTUP_DOTS: u8 = 6;
+// Dots taken by fetching an object's tile data, during which no pixels are output
+const OBJ_FETCH_DOTS: u8 = 6;
+
+pub fn line_objects<'a>(oam: &'a [u8], io_regs: &[u8]) -> impl Iterator<Item = &'a [u8]> {
+    // The OAM entries OAM scan selects for the current line: the first 10 covering it, in OAM order.
+    // Objects off the sides of the screen count too.
+    let ly = io_regs[0x0044] as u16;
+    let sprite_height = if io_regs[0x0040] & 0b00000100 != 0 { 16 } else { 8 };
+    oam.chunks_exact(4)
+        .filter(move |entry| ly + 16 >= entry[0] as u16 && ly + 16 < entry[0] as u16 + sprite_height)
+        .take(10)
+}
+
+#[derive(Clone)]
+pub struct FifoRenderer {
+    line: [u8; LCD_WIDTH],
+    ly: u8,
+    // Number of pixels output so far
+    lx: u8,
+    // Pixels still to be thrown away before output starts (fine scrolling with SCX, or a window left of the screen)
+    discard: u8,
+    startup: u8,
+
+    bg_fifo: VecDeque<u8>,
+    obj_fifo: VecDeque<ObjPixel>,
+
+    step: FetchStep,
+    step_dots: u8,
+    // Tile column the fetcher is working on, counted from the start of the line (or window)
+    fetch_x: u8,
+    tile: u8,
+    data_low: u8,
+    data_high: u8,
+
+    // Whether WY has matched LY so far this frame, and the window's own line counter
+    window_reached: bool,
+    window_line: u16,
+    // Whether the fetcher switched to the window on this line
+    window: bool,
+
+    // Objects found during the OAM scan, and the one currently being fetched (with the dots spent on it)
+    sprites: Vec<Sprite>,
+    obj_fetch: Option<(usize, u8)>,
+}
+
+impl FifoRenderer {
+    pub fn new() -> Self {
+        FifoRenderer {
+            line: [0; LCD_WIDTH],
+            ly: 0,
+            lx: 0,
+            discard: 0,
+            startup: 0,
+
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+
+            step: FetchStep::TileNumber,
+            step_dots: 0,
+            fetch_x: 0,
+            tile: 0,
+            data_low: 0,
+            data_high: 0,
+
+            window_reached: false,
+            window_line: 0,
+            window: false,
+
+            sprites: Vec::with_capacity(10),
+            obj_fetch: None,
+        }
+    }
+
+    pub fn start_line(&mut self, oam: &[u8], io_regs: &[u8], window_reached: bool, window_line: u16) {
+        // Called at the start of mode 3, with the OAM scan's results being taken from OAM right away
+        let ly = io_regs[0x0044];
+        self.line = [0; LCD_WIDTH];
+        self.ly = ly;
+        self.lx = 0;
+        self.discard = io_regs[0x0043] % 8;
+        self.startup = STARTUP_DOTS;
+
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.step = FetchStep::TileNumber;
+        self.step_dots = 0;
+        self.fetch_x = 0;
+
+        self.window_reached = window_reached;
+        self.window_line = window_line;
+        self.window = false;
+
+        self.sprites.clear();
+        self.obj_fetch = None;
+        for entry in line_objects(oam, io_regs) {
+            self.sprites.push(Sprite { y: entry[0], x: entry[1], tile: entry[2], flags: entry[3], fetched: false });
+        }
+    }
+
+    pub fn line(&self) -> &[u8; LCD_WIDTH] {
+        &self.line
+    }
+
+    pub fn line_done(&self) -> bool {
+        // Whether the whole line has been output
+        self.lx as usize == LCD_WIDTH
+    }
+
+    pub fn window_drawn(&self) -> bool {
+        // Whether the window was drawn on this line, which advances the window line counter
+        self.window
+    }
+
+    pub fn dot(&mut self, vram: &[u8], io_regs: &[u8]) -> bool {
+        // Advances mode 3 by a single dot. Returns true once the whole line has been output.
+        let lcdc = io_regs[0x0040];
+
+        if self.startup > 0 {
+            self.startup -= 1;
+            return false;
+        }
+
+        // Fetching an object stalls everything else
+        if let Some((i, dots)) = self.obj_fetch {
+            let dots = dots + 1;
+            if dots == OBJ_FETCH_DOTS {
+                self.fetch_sprite(i, vram, io_regs);
+                self.obj_fetch = None;
+            } else {
+                self.obj_fetch = Some((i, dots));
+            }
+            return false;
+        }
+
+        // Switch to the window once its left edge is reached. The fetcher starts over, and the FIFO is thrown away.
+        let wx = io_regs[0x004B];
+        if !self.window && lcdc & 0b00100000 != 0 && self.window_reached && wx <= 166 && self.lx + 7 >= wx {
+            self.window = true;
+            self.bg_fifo.clear();
+            self.step = FetchStep::TileNumber;
+            self.step_dots = 0;
+            self.fetch_x = 0;
+            self.discard = 7u8.saturating_sub(wx);
+        }
+
+        // An object starting at this pixel gets fetched, once the background fetcher has finished its current tile
+        if lcdc & 0b00000010 != 0 && self.discard == 0 {
+            let lx = self.lx;
+            if let Some(i) = self.sprites.iter().position(|s| !s.fetched && s.x <= lx + 8) {
+                let fetcher_idle = self.step == FetchStep::Push || (self.step == FetchStep::TileNumber && self.step_dots == 0);
+                if fetcher_idle && !self.bg_fifo.is_empty() {
+                    self.obj_fetch = Some((i, 1));
+                } else {
+                    self.advance_fetcher(vram, io_regs);
+                }
+                return false;
+            }
+        }
+
+        self.advance_fetcher(vram, io_regs);
+
+        // Shift a pixel out to the LCD
+        if let Some(bg) = self.bg_fifo.pop_front() {
+            let obj = self.obj_fifo.pop_front();
+            if self.discard > 0 {
+                self.discard -= 1;
+                return false;
+            }
+
+            self.line[self.lx as usize] = Self::mix(bg, obj, io_regs);
+            self.lx += 1;
+            return self.lx as usize == LCD_WIDTH;
+        }
+        false
+    }
+
+    fn advance_fetcher(&mut self, vram: &[u8], io_regs: &[u8]) {
+        if self.step == FetchStep::Push {
+            // The tile can only be pushed once the FIFO has run empty
+            if self.bg_fifo.is_empty() {
+                for bit in (0..8).rev() {
+                    self.bg_fifo.push_back(((self.data_low >> bit) & 1) | (((self.data_high >> bit) & 1) << 1));
+                }
+                self.fetch_x = self.fetch_x.wrapping_add(1);
+                self.step = FetchStep::TileNumber;
+            }
+            return;
+        }
+
+        // The other steps do their work on their second dot
+        self.step_dots += 1;
+        if self.step_dots < 2 {
+            return;
+        }
+        self.step_dots = 0;
+
+        self.step = match self.step {
+            FetchStep::TileNumber => {
+                self.tile = vram[self.tile_map_addr(io_regs)];
+                FetchStep::DataLow
+            }
+            FetchStep::DataLow => {
+                self.data_low = vram[self.tile_data_addr(io_regs)];
+                FetchStep::DataHigh
+            }
+            FetchStep::DataHigh => {
+                self.data_high = vram[self.tile_data_addr(io_regs) + 1];
+                FetchStep::Push
+            }
+            FetchStep::Push => FetchStep::Push,
+        };
+    }
+
+    fn tile_map_addr(&self, io_regs: &[u8]) -> usize {
+        let lcdc = io_regs[0x0040];
+        let (map, x, y) = if self.window {
+            let map = if lcdc & 0b01000000 != 0 { 0x1C00 } else { 0x1800 };
+            (map, self.fetch_x as u16, self.window_line)
+        } else {
+            // SCX and SCY are read on every fetch, so changing them mid-line works (at tile granularity)
+            let (scy, scx) = (io_regs[0x0042], io_regs[0x0043]);
+            let map = if lcdc & 0b00001000 != 0 { 0x1C00 } else { 0x1800 };
+            (map, (scx / 8) as u16 + self.fetch_x as u16, (self.ly as u16 + scy as u16) & 0xFF)
+        };
+        (map + (x & 0x1F) + ((y / 8) & 0x1F) * 32) as usize
+    }
+
+    fn tile_data_addr(&self, io_regs: &[u8]) -> usize {
+        let lcdc = io_regs[0x0040];
+        let row = if self.window {
+            self.window_line % 8
+        } else {
+            (self.ly as u16 + io_regs[0x0042] as u16) % 8
+        };
+        let tile = if lcdc & 0b00010000 != 0 {
+            self.tile as u16 * 16
+        } else {
+            0x0800 + (self.tile as i8 as i16 + 128) as u16 * 16
+        };
+        (tile + row * 2) as usize
+    }
+
+    fn fetch_sprite(&mut self, i: usize, vram: &[u8], io_regs: &[u8]) {
+        let sprite = self.sprites[i];
+        self.sprites[i].fetched = true;
+
+        let tall = io_regs[0x0040] & 0b00000100 != 0;
+        let height = if tall { 16 } else { 8 };
+        // Objects were selected with the height at the start of the line, so keep the row in range if it changed since
+        let mut row = (self.ly as u16 + 16 - sprite.y as u16) & (height - 1);
+        if sprite.flags & 0b01000000 != 0 { row = height - 1 - row; }
+        let tile = if tall { sprite.tile & 0xFE } else { sprite.tile } as u16;
+        let addr = (tile * 16 + row * 2) as usize;
+        let (low, high) = (vram[addr], vram[addr + 1]);
+
+        let xflip = sprite.flags & 0b00100000 != 0;
+        let palette = (sprite.flags >> 4) & 1;
+        let bg_priority = sprite.flags & 0b10000000 != 0;
+
+        // Pixels left of the current position (off the left edge of the screen) are dropped
+        let skip = (self.lx + 8 - sprite.x) as usize;
+        for px in skip..8 {
+            let bit = if xflip { px } else { 7 - px };
+            let color = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+            let pixel = ObjPixel { color, palette, bg_priority };
+
+            // Objects fetched earlier have priority, so only fill in where they're transparent
+            match self.obj_fifo.get_mut(px - skip) {
+                Some(existing) => if existing.color == 0 { *existing = pixel },
+                None => self.obj_fifo.push_back(pixel),
+            }
+        }
+    }
+
+    fn mix(bg: u8, obj: Option<ObjPixel>, io_regs: &[u8]) -> u8 {
+        // Palettes are applied as the pixel is output, so changing them mid-line works
+        let lcdc = io_regs[0x0040];
+        // With LCDC bit 0 clear, background and window are blank
+        let bg = if lcdc & 0b00000001 != 0 { bg } else { 0 };
+
+        match obj {
+            Some(obj) if lcdc & 0b00000010 != 0 && obj.color != 0 && (!obj.bg_priority || bg == 0) => {
+                let palette = if obj.palette == 1 { io_regs[0x0049] } else { io_regs[0x0048] };
+                (palette >> (obj.color * 2)) & 0x3
+            }
+            _ => (io_regs[0x0047] >> (bg * 2)) & 0x3,
+        }
+    }
+}
+
+impl Default for FifoRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for FifoRenderer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.line);
+        w.write_bytes(&[self.ly, self.lx, self.discard, self.startup]);
+
+        w.write_slice(&self.bg_fifo.iter().copied().collect::<Vec<u8>>());
+        w.write_slice(&self.obj_fifo.iter()
+            .flat_map(|p| [p.color, p.palette, p.bg_priority as u8])
+            .collect::<Vec<u8>>());
+
+        w.write_u8(match self.step {
+            FetchStep::TileNumber => 0,
+            FetchStep::DataLow => 1,
+            FetchStep::DataHigh => 2,
+            FetchStep::Push => 3,
+        });
+        w.write_bytes(&[self.step_dots, self.fetch_x, self.tile, self.data_low, self.data_high]);
+
+        w.write_bool(self.window_reached);
+        w.write_u16(self.window_line);
+        w.write_bool(self.window);
+
+        w.write_slice(&self.sprites.iter()
+            .flat_map(|s| [s.y, s.x, s.tile, s.flags, s.fetched as u8])
+            .collect::<Vec<u8>>());
+        match self.obj_fetch {
+            Some((i, dots)) => w.write_bytes(&[1, i as u8, dots]),
+            None => w.write_bytes(&[0, 0, 0]),
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_bytes(&mut self.line)?;
+        let mut bytes = [0; 4];
+        r.read_bytes(&mut bytes)?;
+        let [ly, lx, discard, startup] = bytes;
+        // Pixels are palette indices, and only lines on screen are ever drawn
+        if self.line.iter().any(|&color| color > 3) || ly as usize >= LCD_HEIGHT || lx as usize > LCD_WIDTH {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.ly = ly;
+        self.lx = lx;
+        self.discard = discard;
+        self.startup = startup;
+
+        // Both FIFOs hold at most a tile's worth of pixels, each a color index
+        let bg_fifo = r.read_slice()?;
+        if bg_fifo.len() > 8 || bg_fifo.iter().any(|&color| color > 3) {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.bg_fifo = bg_fifo.into_iter().collect();
+        let obj_fifo = r.read_slice()?;
+        if obj_fifo.len() % 3 != 0 || obj_fifo.len() / 3 > 8 || obj_fifo.chunks_exact(3).any(|p| p[0] > 3 || p[1] > 1) {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.obj_fifo = obj_fifo.chunks_exact(3)
+            .map(|p| ObjPixel { color: p[0], palette: p[1], bg_priority: p[2] != 0 })
+            .collect();
+
+        self.step = match r.read_u8()? {
+            0 => FetchStep::TileNumber,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return Err(SavestateError::InvalidFormat),
+        };
+        let mut bytes = [0; 5];
+        r.read_bytes(&mut bytes)?;
+        let [step_dots, fetch_x, tile, data_low, data_high] = bytes;
+        self.step_dots = step_dots;
+        self.fetch_x = fetch_x;
+        self.tile = tile;
+        self.data_low = data_low;
+        self.data_high = data_high;
+
+        self.window_reached = r.read_bool()?;
+        self.window_line = r.read_u16()?;
+        self.window = r.read_bool()?;
+        if self.window_line > LCD_HEIGHT as u16 {
+            return Err(SavestateError::InvalidFormat);
+        }
+
+        // Objects were picked because they're on this line, which fetching them relies on
+        let sprites = r.read_slice()?;
+        if sprites.len() % 5 != 0 || sprites.len() / 5 > 10 || sprites.chunks_exact(5).any(|s| s[0] as u16 > ly as u16 + 16) {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.sprites = sprites.chunks_exact(5)
+            .map(|s| Sprite { y: s[0], x: s[1], tile: s[2], flags: s[3], fetched: s[4] != 0 })
+            .collect();
+        let mut bytes = [0; 3];
+        r.read_bytes(&mut bytes)?;
+        self.obj_fetch = match bytes {
+            [0, _, _] => None,
+            [_, i, dots] if (i as usize) < self.sprites.len() && self.sprites[i as usize].x <= lx + 8 && dots < OBJ_FETCH_DOTS => {
+                Some((i as usize, dots))
+            }
+            _ => return Err(SavestateError::InvalidFormat),
+        };
+        Ok(())
+    }
+}
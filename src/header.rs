@@ -0,0 +1,281 @@
+use std::error::Error;
+use std::fmt;
+
+// The header occupies 0x0100-0x014F, so no valid ROM can be shorter than this
+const HEADER_END: usize = 0x0150;
+
+// Cartridge RAM sizes for header byte 0x0149 (code 1 was never used in a released cartridge)
+const RAM_SIZES: [usize; 6] = [0, 0, 8192, 32768, 131072, 65536];
+
+// The logo bitmap every licensed cartridge carries at 0x0104-0x0133
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    // The file is too short to even contain a header
+    TooSmall { len: usize },
+    // The ROM is shorter than the size given in its header
+    Truncated { expected: usize, actual: usize },
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnsupportedType(CartridgeKind),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall { len } =>
+                write!(f, "ROM is only {} bytes long, too short to contain a cartridge header", len),
+            CartridgeError::Truncated { expected, actual } =>
+                write!(f, "ROM is truncated: header says {} bytes, but the file has {}", expected, actual),
+            CartridgeError::InvalidRomSize(code) => write!(f, "invalid ROM size code {:#04X} in cartridge header", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "invalid RAM size code {:#04X} in cartridge header", code),
+            CartridgeError::UnsupportedType(kind) => write!(f, "unsupported cartridge type: {}", kind),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+// Cartridge type, from header byte 0x0147
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartridgeKind {
+    RomOnly,
+    Mbc1,
+    Mbc1Ram,
+    Mbc1RamBattery,
+    Mbc2,
+    Mbc2Battery,
+    RomRam,
+    RomRamBattery,
+    Mmm01,
+    Mmm01Ram,
+    Mmm01RamBattery,
+    Mbc3TimerBattery,
+    Mbc3TimerRamBattery,
+    Mbc3,
+    Mbc3Ram,
+    Mbc3RamBattery,
+    Mbc5,
+    Mbc5Ram,
+    Mbc5RamBattery,
+    Mbc5Rumble,
+    Mbc5RumbleRam,
+    Mbc5RumbleRamBattery,
+    Mbc6,
+    Mbc7SensorRumbleRamBattery,
+    PocketCamera,
+    BandaiTama5,
+    HuC3,
+    HuC1RamBattery,
+    Unknown(u8),
+}
+
+impl CartridgeKind {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => CartridgeKind::RomOnly,
+            0x01 => CartridgeKind::Mbc1,
+            0x02 => CartridgeKind::Mbc1Ram,
+            0x03 => CartridgeKind::Mbc1RamBattery,
+            0x05 => CartridgeKind::Mbc2,
+            0x06 => CartridgeKind::Mbc2Battery,
+            0x08 => CartridgeKind::RomRam,
+            0x09 => CartridgeKind::RomRamBattery,
+            0x0B => CartridgeKind::Mmm01,
+            0x0C => CartridgeKind::Mmm01Ram,
+            0x0D => CartridgeKind::Mmm01RamBattery,
+            0x0F => CartridgeKind::Mbc3TimerBattery,
+            0x10 => CartridgeKind::Mbc3TimerRamBattery,
+            0x11 => CartridgeKind::Mbc3,
+            0x12 => CartridgeKind::Mbc3Ram,
+            0x13 => CartridgeKind::Mbc3RamBattery,
+            0x19 => CartridgeKind::Mbc5,
+            0x1A => CartridgeKind::Mbc5Ram,
+            0x1B => CartridgeKind::Mbc5RamBattery,
+            0x1C => CartridgeKind::Mbc5Rumble,
+            0x1D => CartridgeKind::Mbc5RumbleRam,
+            0x1E => CartridgeKind::Mbc5RumbleRamBattery,
+            0x20 => CartridgeKind::Mbc6,
+            0x22 => CartridgeKind::Mbc7SensorRumbleRamBattery,
+            0xFC => CartridgeKind::PocketCamera,
+            0xFD => CartridgeKind::BandaiTama5,
+            0xFE => CartridgeKind::HuC3,
+            0xFF => CartridgeKind::HuC1RamBattery,
+            _ => CartridgeKind::Unknown(code),
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self, CartridgeKind::Mbc1RamBattery | CartridgeKind::Mbc2Battery | CartridgeKind::RomRamBattery
+            | CartridgeKind::Mmm01RamBattery | CartridgeKind::Mbc3TimerBattery | CartridgeKind::Mbc3TimerRamBattery
+            | CartridgeKind::Mbc3RamBattery | CartridgeKind::Mbc5RamBattery | CartridgeKind::Mbc5RumbleRamBattery
+            | CartridgeKind::Mbc7SensorRumbleRamBattery | CartridgeKind::HuC1RamBattery)
+    }
+
+    pub fn has_timer(&self) -> bool {
+        matches!(self, CartridgeKind::Mbc3TimerBattery | CartridgeKind::Mbc3TimerRamBattery)
+    }
+
+    pub fn has_rumble(&self) -> bool {
+        matches!(self, CartridgeKind::Mbc5Rumble | CartridgeKind::Mbc5RumbleRam | CartridgeKind::Mbc5RumbleRamBattery
+            | CartridgeKind::Mbc7SensorRumbleRamBattery)
+    }
+}
+
+impl fmt::Display for CartridgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CartridgeKind::RomOnly => "ROM ONLY",
+            CartridgeKind::Mbc1 => "MBC1",
+            CartridgeKind::Mbc1Ram => "MBC1+RAM",
+            CartridgeKind::Mbc1RamBattery => "MBC1+RAM+BATTERY",
+            CartridgeKind::Mbc2 => "MBC2",
+            CartridgeKind::Mbc2Battery => "MBC2+BATTERY",
+            CartridgeKind::RomRam => "ROM+RAM",
+            CartridgeKind::RomRamBattery => "ROM+RAM+BATTERY",
+            CartridgeKind::Mmm01 => "MMM01",
+            CartridgeKind::Mmm01Ram => "MMM01+RAM",
+            CartridgeKind::Mmm01RamBattery => "MMM01+RAM+BATTERY",
+            CartridgeKind::Mbc3TimerBattery => "MBC3+TIMER+BATTERY",
+            CartridgeKind::Mbc3TimerRamBattery => "MBC3+TIMER+RAM+BATTERY",
+            CartridgeKind::Mbc3 => "MBC3",
+            CartridgeKind::Mbc3Ram => "MBC3+RAM",
+            CartridgeKind::Mbc3RamBattery => "MBC3+RAM+BATTERY",
+            CartridgeKind::Mbc5 => "MBC5",
+            CartridgeKind::Mbc5Ram => "MBC5+RAM",
+            CartridgeKind::Mbc5RamBattery => "MBC5+RAM+BATTERY",
+            CartridgeKind::Mbc5Rumble => "MBC5+RUMBLE",
+            CartridgeKind::Mbc5RumbleRam => "MBC5+RUMBLE+RAM",
+            CartridgeKind::Mbc5RumbleRamBattery => "MBC5+RUMBLE+RAM+BATTERY",
+            CartridgeKind::Mbc6 => "MBC6",
+            CartridgeKind::Mbc7SensorRumbleRamBattery => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+            CartridgeKind::PocketCamera => "POCKET CAMERA",
+            CartridgeKind::BandaiTama5 => "BANDAI TAMA5",
+            CartridgeKind::HuC3 => "HuC3",
+            CartridgeKind::HuC1RamBattery => "HuC1+RAM+BATTERY",
+            CartridgeKind::Unknown(code) => return write!(f, "unknown ({:#04X})", code),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Header byte 0x0143
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    DmgOnly,
+    // Works on both DMG and CGB, with CGB enhancements
+    Enhanced,
+    CgbOnly,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Licensee {
+    // Header byte 0x014B
+    Old(u8),
+    // Two ASCII characters at 0x0144-0x0145, used when the old licensee code is 0x33
+    New(String),
+}
+
+pub struct CartridgeHeader {
+    pub title: String,
+    // Only present in newer cartridges, where it takes the last 4 bytes of the title area
+    pub manufacturer_code: Option<String>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub licensee: Licensee,
+    pub kind: CartridgeKind,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    // 0x014A: 0x00 for Japan, 0x01 for everywhere else
+    pub destination: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+
+    // Verification results. The DMG boot ROM refuses to run a cartridge with a bad logo or header checksum,
+    // but only the values are exposed here: plenty of homebrew and test ROMs get them wrong.
+    pub logo_valid: bool,
+    pub header_checksum_valid: bool,
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall { len: rom.len() });
+        }
+
+        let cgb = match rom[0x0143] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::CgbOnly,
+            _ => CgbSupport::DmgOnly,
+        };
+
+        // In older cartridges the title takes up all of 0x0134-0x0143. Newer ones shortened it to make room
+        // for the CGB flag and a manufacturer code, which is only recognisable by being 4 uppercase characters.
+        let manufacturer = &rom[0x013F..0x0143];
+        let has_manufacturer = cgb != CgbSupport::DmgOnly
+            && manufacturer.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let title_end = if has_manufacturer { 0x013F } else if cgb != CgbSupport::DmgOnly { 0x0143 } else { 0x0144 };
+
+        let licensee = match rom[0x014B] {
+            0x33 => Licensee::New(ascii_string(&rom[0x0144..0x0146])),
+            code => Licensee::Old(code),
+        };
+
+        let rom_size_code = rom[0x0148];
+        if rom_size_code > 0x08 {
+            return Err(CartridgeError::InvalidRomSize(rom_size_code));
+        }
+        let rom_size = 32768 << rom_size_code;
+        if rom.len() < rom_size {
+            return Err(CartridgeError::Truncated { expected: rom_size, actual: rom.len() });
+        }
+
+        let ram_size_code = rom[0x0149];
+        let ram_size = *RAM_SIZES.get(ram_size_code as usize).ok_or(CartridgeError::InvalidRamSize(ram_size_code))?;
+
+        let header_checksum = rom[0x014D];
+        let global_checksum = ((rom[0x014E] as u16) << 8) | rom[0x014F] as u16;
+
+        // The header checksum covers 0x0134-0x014C, the global checksum every byte except itself
+        let computed_header = rom[0x0134..0x014D].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+        let computed_global = rom.iter().enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16));
+
+        Ok(CartridgeHeader {
+            title: ascii_string(&rom[0x0134..title_end]),
+            manufacturer_code: if has_manufacturer { Some(ascii_string(manufacturer)) } else { None },
+            cgb,
+            sgb: rom[0x0146] == 0x03,
+            licensee,
+            kind: CartridgeKind::from_code(rom[0x0147]),
+            rom_size,
+            ram_size,
+            destination: rom[0x014A],
+            version: rom[0x014C],
+            header_checksum,
+            global_checksum,
+
+            logo_valid: rom[0x0104..0x0134] == NINTENDO_LOGO,
+            header_checksum_valid: computed_header == header_checksum,
+            global_checksum_valid: computed_global == global_checksum,
+        })
+    }
+}
+
+fn ascii_string(bytes: &[u8]) -> String {
+    // Header strings are padded with zeroes
+    bytes.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
@@ -0,0 +1,124 @@
+use log::trace;
+
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+// Interrupt sources, in priority order. The discriminant is the source's bit in IE and IF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+const ALL_INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank, Interrupt::Stat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad,
+];
+
+impl Interrupt {
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    pub fn vector(self) -> u16 {
+        // Address the CPU jumps to when servicing this interrupt
+        0x0040 + 0x08 * self as u16
+    }
+}
+
+// Owns the Interrupt Enable (0xFFFF) and Interrupt Flag (0xFF0F) registers.
+// Every request and acknowledge goes through here, and is logged at trace level.
+#[derive(Clone)]
+pub struct Interrupts {
+    enable: u8,
+    flags: u8,
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Interrupts { enable: 0, flags: 0 }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            // Only the lower 5 bits of IF exist, the rest read as 1
+            0xFF0F => self.flags | 0xE0,
+            0xFFFF => self.enable,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF0F => self.flags = value & 0x1F,
+            0xFFFF => self.enable = value,
+            _ => (),
+        }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        trace!("interrupt requested: {:?}", interrupt);
+        self.flags |= interrupt.mask();
+    }
+
+    pub fn acknowledge(&mut self, interrupt: Interrupt) {
+        trace!("interrupt acknowledged: {:?}", interrupt);
+        self.flags &= !interrupt.mask();
+    }
+
+    pub fn pending(&self) -> Option<Interrupt> {
+        // The highest priority interrupt that is both requested and enabled
+        let pending = self.enable & self.flags;
+        ALL_INTERRUPTS.iter().copied().find(|i| pending & i.mask() != 0)
+    }
+}
+
+impl Default for Interrupts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Interrupts {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.enable);
+        w.write_u8(self.flags);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.enable = r.read_u8()?;
+        self.flags = r.read_u8()? & 0x1F;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unused_flag_bits_read_as_one() {
+        let mut interrupts = Interrupts::new();
+        assert_eq!(interrupts.read(0xFF0F), 0xE0);
+        interrupts.write(0xFF0F, 0xFF);
+        assert_eq!(interrupts.read(0xFF0F), 0xFF);
+        interrupts.write(0xFF0F, 0x00);
+        interrupts.request(Interrupt::Serial);
+        assert_eq!(interrupts.read(0xFF0F), 0xE8);
+    }
+
+    #[test]
+    fn pending_is_the_highest_priority_enabled_request() {
+        let mut interrupts = Interrupts::new();
+        interrupts.request(Interrupt::Joypad);
+        interrupts.request(Interrupt::Timer);
+        assert_eq!(interrupts.pending(), None);
+
+        interrupts.write(0xFFFF, 0x1F);
+        assert_eq!(interrupts.pending(), Some(Interrupt::Timer));
+        interrupts.acknowledge(Interrupt::Timer);
+        assert_eq!(interrupts.pending(), Some(Interrupt::Joypad));
+        assert_eq!(Interrupt::Joypad.vector(), 0x0060);
+    }
+}
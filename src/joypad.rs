@@ -1,8 +1,8 @@
 use std::collections::HashSet;
 
-use crate::mmu::Mmu;
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum JoypadButton {
     Up,
     Down,
@@ -14,42 +14,104 @@ pub enum JoypadButton {
     Select
 }
 
+const ALL_BUTTONS: [JoypadButton; 8] = [
+    JoypadButton::Up, JoypadButton::Down, JoypadButton::Left, JoypadButton::Right,
+    JoypadButton::A, JoypadButton::B, JoypadButton::Start, JoypadButton::Select,
+];
+
+#[derive(Clone)]
 pub struct Joypad {
-    pressed: HashSet<JoypadButton>
+    pressed: HashSet<JoypadButton>,
+    // Bits 4-5 of P1, selecting the action and/or direction buttons (active low)
+    select: u8,
+    // Set when a selected input line goes low, until the MMU requests the joypad interrupt
+    interrupt: bool,
 }
 
 impl Joypad {
-    pub fn new() -> Self { Joypad { pressed: HashSet::new() } }
+    pub fn new() -> Self { Joypad { pressed: HashSet::new(), select: 0x30, interrupt: false } }
 
     pub fn press(&mut self, button: JoypadButton) {
+        let before = self.lines();
         self.pressed.insert(button);
+        self.check_interrupt(before);
     }
 
     pub fn release(&mut self, button: JoypadButton) {
         self.pressed.remove(&button);
     }
 
-    pub fn tick(&self, mmu: &mut Mmu) {
-        let mut joyp = mmu.read(0xFF00);
-        let action = joyp & 0b00100000 == 0;
-        let direction = joyp & 0b00010000 == 0;
+    pub fn read(&self) -> u8 {
+        // Bits 6-7 are unused and read as 1
+        0xC0 | self.select | self.lines()
+    }
 
-        // Set all pressed flags to 1 (unpressed)
-        joyp |= 0x0F;
+    pub fn write(&mut self, value: u8) {
+        // Only the select bits can be written, but selecting a group with a button held makes its line fall
+        let before = self.lines();
+        self.select = value & 0x30;
+        self.check_interrupt(before);
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::replace(&mut self.interrupt, false)
+    }
+
+    fn lines(&self) -> u8 {
+        // The 4 input lines, low for a pressed button in any selected group
+        let action = self.select & 0b00100000 == 0;
+        let direction = self.select & 0b00010000 == 0;
+
+        let mut lines = 0x0F;
         if action {
-            if self.pressed.contains(&JoypadButton::Start) { joyp &= 0b11110111 }
-            if self.pressed.contains(&JoypadButton::Select) { joyp &= 0b11111011 }
-            if self.pressed.contains(&JoypadButton::B) { joyp &= 0b11111101 }
-            if self.pressed.contains(&JoypadButton::A) { joyp &= 0b11111110 }
+            if self.pressed.contains(&JoypadButton::Start) { lines &= 0b0111 }
+            if self.pressed.contains(&JoypadButton::Select) { lines &= 0b1011 }
+            if self.pressed.contains(&JoypadButton::B) { lines &= 0b1101 }
+            if self.pressed.contains(&JoypadButton::A) { lines &= 0b1110 }
         }
 
         if direction {
-            if self.pressed.contains(&JoypadButton::Down) { joyp &= 0b11110111 }
-            if self.pressed.contains(&JoypadButton::Up) { joyp &= 0b11111011 }
-            if self.pressed.contains(&JoypadButton::Left) { joyp &= 0b11111101 }
-            if self.pressed.contains(&JoypadButton::Right) { joyp &= 0b11111110 }
+            if self.pressed.contains(&JoypadButton::Down) { lines &= 0b0111 }
+            if self.pressed.contains(&JoypadButton::Up) { lines &= 0b1011 }
+            if self.pressed.contains(&JoypadButton::Left) { lines &= 0b1101 }
+            if self.pressed.contains(&JoypadButton::Right) { lines &= 0b1110 }
+        }
+        lines
+    }
+
+    fn check_interrupt(&mut self, before: u8) {
+        // Any input line going low requests the joypad interrupt
+        if before & !self.lines() & 0x0F != 0 {
+            self.interrupt = true;
         }
+    }
+}
 
-        mmu.write(0xFF00, joyp);
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        // Pressed buttons are stored as a bitmask, in the order of ALL_BUTTONS
+        let mask = ALL_BUTTONS.iter().enumerate()
+            .filter(|(_, b)| self.pressed.contains(b))
+            .fold(0u8, |mask, (i, _)| mask | (1 << i));
+        w.write_u8(mask);
+        w.write_u8(self.select);
+        w.write_bool(self.interrupt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        let mask = r.read_u8()?;
+        self.pressed = ALL_BUTTONS.iter().enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, &b)| b)
+            .collect();
+        self.select = r.read_u8()? & 0x30;
+        self.interrupt = r.read_bool()?;
+        Ok(())
+    }
+}
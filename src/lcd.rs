@@ -1,16 +1,48 @@
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+pub const LCD_WIDTH: usize = 160;
+pub const LCD_HEIGHT: usize = 144;
+
+#[derive(Clone)]
 pub struct Lcd {
-    pub pixels: [u8; 23200]
+    pub pixels: [u8; LCD_WIDTH * LCD_HEIGHT]
 }
 
 impl Lcd {
     pub fn new() -> Lcd {
         Lcd {
-            pixels: [0; 23200],
+            pixels: [0; LCD_WIDTH * LCD_HEIGHT],
         }
     }
 
-    pub fn set_line(&mut self, ly: u8, line: [u8; 160]) {
+    pub fn set_line(&mut self, ly: u8, line: [u8; LCD_WIDTH]) {
         let line_num = ly as usize;
-        self.pixels[line_num*160..(line_num+1)*160].copy_from_slice(&line);
+        self.pixels[line_num*LCD_WIDTH..(line_num+1)*LCD_WIDTH].copy_from_slice(&line);
+    }
+
+    pub fn clear(&mut self) {
+        // Blank (white) screen, as shown while the LCD is off
+        self.pixels = [0; LCD_WIDTH * LCD_HEIGHT];
+    }
+}
+
+impl Default for Lcd {
+    fn default() -> Self {
+        Self::new()
     }
-}
\ No newline at end of file
+}
+
+impl Savestate for Lcd {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.pixels);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_bytes(&mut self.pixels)?;
+        // Pixels are shades 0-3
+        if self.pixels.iter().any(|&shade| shade > 3) {
+            return Err(SavestateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
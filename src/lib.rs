@@ -0,0 +1,35 @@
+extern crate enum_dispatch;
+
+pub mod apu;
+pub mod cartridge;
+pub mod cpu;
+pub mod dma;
+pub mod error;
+pub mod fifo;
+pub mod header;
+pub mod interrupts;
+pub mod joypad;
+pub mod lcd;
+pub mod memory;
+pub mod mmu;
+pub mod motherboard;
+pub mod ppu;
+pub mod save;
+pub mod savestate;
+pub mod serial;
+pub mod timers;
+
+pub use crate::apu::Apu;
+pub use crate::cartridge::{load_cartridge, Cartridge, CartridgeType};
+pub use crate::cpu::Cpu;
+pub use crate::error::{EmulatorError, ErrorKind};
+pub use crate::header::{CartridgeError, CartridgeHeader, CartridgeKind};
+pub use crate::interrupts::{Interrupt, Interrupts};
+pub use crate::joypad::{Joypad, JoypadButton};
+pub use crate::lcd::{Lcd, LCD_HEIGHT, LCD_WIDTH};
+pub use crate::mmu::Mmu;
+pub use crate::motherboard::Motherboard;
+pub use crate::ppu::{AccessMode, RenderMode};
+pub use crate::save::BatterySave;
+pub use crate::savestate::SavestateError;
+pub use crate::serial::SerialDevice;
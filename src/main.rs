@@ -1,12 +1,10 @@
-extern crate enum_dispatch;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use std::io::prelude::*;
-use std::io::{self, BufRead};
-use std::fs::File;
-use std::time::Instant;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
-use joypad::{Joypad, JoypadButton};
-use log::error;
+use log::{error, info};
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
 use winit::event::{Event, VirtualKeyCode};
@@ -14,35 +12,27 @@ use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 
-const WIDTH: u32 = 160;
-const HEIGHT: u32 = 144;
-const TARGET_FPS: u32 = 60;
-
-mod cartridge;
-mod cpu;
-mod memory;
-mod motherboard;
-mod mmu;
-mod timers;
-mod ppu;
-mod lcd;
-mod joypad;
-
 use std::{env, fs};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::lcd::Lcd;
-use crate::motherboard::Motherboard;
+use rgbl::serial::StdoutSerial;
+use rgbl::{BatterySave, JoypadButton, Lcd, Motherboard, LCD_HEIGHT, LCD_WIDTH};
 
-fn get_log_string(mb: &Motherboard) -> String {
-    format!("A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})",
-        mb.cpu.regs.a, mb.cpu.regs.flags, mb.cpu.regs.b, mb.cpu.regs.c, mb.cpu.regs.d, mb.cpu.regs.e, mb.cpu.regs.h, mb.cpu.regs.l, mb.cpu.sp, mb.cpu.pc,
-        mb.mmu.read(mb.cpu.pc), mb.mmu.read(mb.cpu.pc+1), mb.mmu.read(mb.cpu.pc+2), mb.mmu.read(mb.cpu.pc+3))
-}
+const TARGET_FPS: u64 = 60;
+// Write battery-backed cartridge RAM to disk about once every second
+const SAVE_INTERVAL_FRAMES: u32 = 60;
+// Emulation speed follows the audio buffer: run an extra frame when less than LOW_WATERMARK
+// video frames worth of audio are queued, skip a frame when there are more than HIGH_WATERMARK
+const AUDIO_LOW_WATERMARK: u32 = 2;
+const AUDIO_HIGH_WATERMARK: u32 = 6;
 
-static CONTROLS: [VirtualKeyCode; 8] = [VirtualKeyCode::Z, VirtualKeyCode::X, VirtualKeyCode::Return, VirtualKeyCode::RShift, 
+static CONTROLS: [VirtualKeyCode; 8] = [VirtualKeyCode::Z, VirtualKeyCode::X, VirtualKeyCode::Return, VirtualKeyCode::RShift,
                     VirtualKeyCode::Left, VirtualKeyCode::Right, VirtualKeyCode::Up, VirtualKeyCode::Down];
 
+// F1-F9 load save state slots 1-9, Shift+F1-F9 save to them
+static STATE_SLOTS: [VirtualKeyCode; 9] = [VirtualKeyCode::F1, VirtualKeyCode::F2, VirtualKeyCode::F3, VirtualKeyCode::F4,
+                    VirtualKeyCode::F5, VirtualKeyCode::F6, VirtualKeyCode::F7, VirtualKeyCode::F8, VirtualKeyCode::F9];
+
 fn control(key: VirtualKeyCode) -> JoypadButton {
     match key {
         VirtualKeyCode::Z => JoypadButton::A,
@@ -62,7 +52,21 @@ fn main() -> Result<(), Error> {
     let cart_path = if args.len() > 1 {Path::new(&args[1])} else {Path::new("./carts/blargg_roms/01-special.gb")};
 
     let cart_data = fs::read(cart_path).expect("could not open file");
-    let mut mb = Motherboard::new(&cart_data);
+    let mut mb = match Motherboard::new(&cart_data) {
+        Ok(mb) => mb,
+        Err(e) => {
+            eprintln!("could not load {}: {}", cart_path.display(), e);
+            std::process::exit(1);
+        }
+    };
+    // Test ROMs print their results over the serial port
+    mb.set_serial_device(Box::new(StdoutSerial));
+
+    let rom_path = cart_path.to_path_buf();
+    let mut battery_save = BatterySave::for_rom(cart_path);
+    if let Err(e) = battery_save.load(mb.mmu.cartridge_mut()) {
+        error!("could not load save file {}: {}", battery_save.path().display(), e);
+    }
 
     // let mut logfile = File::create("./carts/logs/log.txt").expect("Could not create log file");
     // let ref_file = File::open("./carts/reference_logs/blargg11.txt").expect("Could not open reference log");
@@ -72,29 +76,24 @@ fn main() -> Result<(), Error> {
     // loop {
     //     // Reference logs skip all execution below PC 0x0100
     //     // if mb.cpu.pc >= 0x0100 {
-    //     //     let log_string = get_log_string(&mb);
+    //     //     let log_string = mb.cpu.log_string(&mb.mmu);
     //     //     writeln!(&mut logfile, "{}", log_string).expect("could not write to log");
     //         // let ref_string = ref_lines.next().expect("error reading reference log").expect("reference log finished!");
     //         // if !(log_string == ref_string) {
     //         //     mb.tick();
-    //         //     writeln!(&mut logfile, "{}", get_log_string(&mb)).expect("");
+    //         //     writeln!(&mut logfile, "{}", mb.cpu.log_string(&mb.mmu)).expect("");
     //         //     panic!("reference log mismatch: expected\n{}\nbut got\n{}", ref_string, log_string);
     //         // }
     //     // }
 
     //     mb.tick();
-    //     if mb.mmu.read(0xFF02) == 0x81 {
-    //         print!("{}", mb.mmu.read(0xFF01) as char);
-    //         io::stdout().flush().ok().expect("could not flush stdout");
-    //         mb.mmu.write(0xFF02, 0);
-    //     }
     // }
 
     env_logger::init();
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
     let window = {
-        let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
+        let size = LogicalSize::new(LCD_WIDTH as f64, LCD_HEIGHT as f64);
         WindowBuilder::new()
             .with_title("RGBL")
             .with_inner_size(size)
@@ -106,43 +105,73 @@ fn main() -> Result<(), Error> {
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(WIDTH, HEIGHT, surface_texture)?
+        Pixels::new(LCD_WIDTH as u32, LCD_HEIGHT as u32, surface_texture)?
     };
 
-    let mut cycle_count:u32 = 0;
+    let audio = AudioOutput::new();
+    if let Some(audio) = &audio {
+        mb.set_sample_rate(audio.sample_rate);
+    }
 
-    let mut frame_start = Instant::now();
+    let frame_time = Duration::from_micros(1_000_000 / TARGET_FPS);
+    let mut next_frame = Instant::now();
+    let mut frames_since_save: u32 = 0;
+    let mut cpu_locked = false;
 
     event_loop.run(move |event, _, control_flow| {
-        cycle_count += mb.tick() as u32;
-        if mb.mmu.read(0xFF02) == 0x81 {
-            print!("{}", mb.mmu.read(0xFF01) as char);
-            io::stdout().flush().ok().expect("could not flush stdout");
-            mb.mmu.write(0xFF02, 0);
+        if let Event::LoopDestroyed = event {
+            flush_save(&mut battery_save, &mb);
+            return;
         }
 
-        if cycle_count >= 70224 {
-            draw_lcd(&mb.lcd, pixels.get_frame(), &mb);
-            if pixels
-                .render()
-                .map_err(|e| error!("pixels.render() failed: {}", e))
-                .is_err()
-            {
-                *control_flow = ControlFlow::Exit;
-                return;
-            }
-
-            cycle_count -= 70224;
-
+        if let Event::MainEventsCleared = event {
             // Wait to conserve framerate
-            let elapsed_time = Instant::now().duration_since(frame_start).as_millis() as u32;
-            let wait_millis = match 1000 / TARGET_FPS >= elapsed_time {
-                true => 1000 / TARGET_FPS - elapsed_time,
-                false => 0,
-            };
-            let new_inst = frame_start + std::time::Duration::from_millis(wait_millis as u64);
-            *control_flow = ControlFlow::WaitUntil(new_inst);
-            frame_start = Instant::now();
+            if Instant::now() >= next_frame {
+                let frames_to_run = match &audio {
+                    Some(audio) => {
+                        let buffered = audio.buffered_frames();
+                        let frame_samples = audio.sample_rate as usize / TARGET_FPS as usize;
+                        if buffered < AUDIO_LOW_WATERMARK as usize * frame_samples { 2 }
+                        else if buffered > AUDIO_HIGH_WATERMARK as usize * frame_samples { 0 }
+                        else { 1 }
+                    }
+                    None => 1,
+                };
+
+                for _ in 0..frames_to_run {
+                    // A locked up CPU is reported once, after which the rest of the system keeps running like the hardware
+                    if let Err(e) = mb.run_frame() {
+                        error!("{}", e);
+                    }
+                    let samples = mb.take_audio_samples();
+                    if let Some(audio) = &audio { audio.queue(&samples); }
+
+                    frames_since_save += 1;
+                    if frames_since_save >= SAVE_INTERVAL_FRAMES {
+                        flush_save(&mut battery_save, &mb);
+                        frames_since_save = 0;
+                    }
+                }
+                if mb.cpu_locked() != cpu_locked {
+                    cpu_locked = mb.cpu_locked();
+                    window.set_title(if cpu_locked { "RGBL (CPU locked up)" } else { "RGBL" });
+                }
+                draw_lcd(mb.lcd(), pixels.get_frame());
+
+                if pixels
+                    .render()
+                    .map_err(|e| error!("pixels.render() failed: {}", e))
+                    .is_err()
+                {
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+
+                next_frame += frame_time;
+                // Don't try to catch up if we fell far behind (e.g. the window was being dragged)
+                if next_frame < Instant::now() { next_frame = Instant::now() + frame_time; }
+            }
+            *control_flow = ControlFlow::WaitUntil(next_frame);
         }
 
         // Handle input events
@@ -154,8 +183,19 @@ fn main() -> Result<(), Error> {
             }
 
             for ctr in CONTROLS {
-                if input.key_pressed(ctr) { mb.joypad.press(control(ctr)) }
-                if input.key_released(ctr) { mb.joypad.release(control(ctr)) }
+                if input.key_pressed(ctr) { mb.joypad_mut().press(control(ctr)) }
+                if input.key_released(ctr) { mb.joypad_mut().release(control(ctr)) }
+            }
+
+            for (i, key) in STATE_SLOTS.iter().enumerate() {
+                if input.key_pressed(*key) {
+                    let slot = i + 1;
+                    if input.held_shift() {
+                        save_state(&mb, &rom_path, slot);
+                    } else {
+                        load_state(&mut mb, &rom_path, slot);
+                    }
+                }
             }
         };
     });
@@ -163,28 +203,102 @@ fn main() -> Result<(), Error> {
 }
 
 
-fn draw_lcd(lcd: &Lcd, frame: &mut [u8], mb: &Motherboard) {
-    for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
+struct AudioOutput {
+    // The stream stops playing when dropped
+    _stream: cpal::Stream,
+    buffer: Arc<Mutex<VecDeque<f32>>>,
+    sample_rate: u32,
+}
 
-        // let (x, y) = ((i % 256) as u16, (i / 256) as u16);
-        // let tile_num = mb.mmu.read(0x9800 + ((x / 8) + (y / 8) * 32)) as u16;
-
-        // let addr = 0x8000 + tile_num * 16 + (y % 8) * 2;
-        // let tile1 = mb.mmu.read(addr as u16);
-        // let tile2 = mb.mmu.read((addr+1) as u16);
-        
-        // let pxidx = 7 - i % 8;
-        // let px = if tile1 & (1 << pxidx) != 0 { 1 } else { 0 } 
-        //                 | if tile2 & (1 << pxidx) != 0 { 2 } else { 0 };
-
-        // let c = match px {
-        //     3 => [0, 0, 0, 255],
-        //     2 => [100, 100, 100, 255],
-        //     1 => [175, 175, 175, 255],
-        //     0 => [255, 255, 255, 255],
-        //     _ => panic!("invalid color code")
-        // };
+impl AudioOutput {
+    fn new() -> Option<Self> {
+        // Open the default audio output device. Without one, the emulator just runs silently.
+        let device = cpal::default_host().default_output_device()?;
+        let supported = device.default_output_config()
+            .map_err(|e| error!("could not get audio output config: {}", e))
+            .ok()?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+
+        let buffer = Arc::new(Mutex::new(VecDeque::new()));
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => build_audio_stream::<f32>(&device, &config, buffer.clone()),
+            cpal::SampleFormat::I16 => build_audio_stream::<i16>(&device, &config, buffer.clone()),
+            cpal::SampleFormat::U16 => build_audio_stream::<u16>(&device, &config, buffer.clone()),
+        }.map_err(|e| error!("could not open audio stream: {}", e)).ok()?;
+        stream.play().map_err(|e| error!("could not start audio stream: {}", e)).ok()?;
+
+        Some(AudioOutput { _stream: stream, buffer, sample_rate: config.sample_rate.0 })
+    }
+
+    fn queue(&self, samples: &[f32]) {
+        self.buffer.lock().unwrap().extend(samples);
+    }
 
+    fn buffered_frames(&self) -> usize {
+        self.buffer.lock().unwrap().len() / 2
+    }
+}
+
+fn build_audio_stream<T: cpal::Sample>(device: &cpal::Device, config: &cpal::StreamConfig, buffer: Arc<Mutex<VecDeque<f32>>>)
+        -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let channels = config.channels as usize;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            let mut buffer = buffer.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                // Play silence if the emulator can't keep up
+                let left = buffer.pop_front().unwrap_or(0.0);
+                let right = buffer.pop_front().unwrap_or(0.0);
+                if channels == 1 {
+                    frame[0] = T::from(&((left + right) / 2.0));
+                } else {
+                    for (i, sample) in frame.iter_mut().enumerate() {
+                        *sample = T::from(&match i { 0 => left, 1 => right, _ => 0.0 });
+                    }
+                }
+            }
+        },
+        |e| error!("audio stream error: {}", e),
+    )
+}
+
+
+fn flush_save(battery_save: &mut BatterySave, mb: &Motherboard) {
+    if let Err(e) = battery_save.flush(mb.mmu.cartridge()) {
+        error!("could not write save file {}: {}", battery_save.path().display(), e);
+    }
+}
+
+
+fn state_path(rom_path: &Path, slot: usize) -> PathBuf {
+    // Save states live next to the ROM: game.gb -> game.ss1, game.ss2, ...
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+fn save_state(mb: &Motherboard, rom_path: &Path, slot: usize) {
+    let path = state_path(rom_path, slot);
+    match fs::write(&path, mb.save_state()) {
+        Ok(()) => info!("saved state to slot {}", slot),
+        Err(e) => error!("could not write save state {}: {}", path.display(), e),
+    }
+}
+
+fn load_state(mb: &mut Motherboard, rom_path: &Path, slot: usize) {
+    let path = state_path(rom_path, slot);
+    let result = fs::read(&path)
+        .map_err(rgbl::SavestateError::from)
+        .and_then(|data| mb.load_state(&data));
+    match result {
+        Ok(()) => info!("loaded state from slot {}", slot),
+        Err(e) => error!("could not load save state {}: {}", path.display(), e),
+    }
+}
+
+
+fn draw_lcd(lcd: &Lcd, frame: &mut [u8]) {
+    for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
         let c = match lcd.pixels[i] {
             3 => [0, 0, 0, 255],
             2 => [100, 100, 100, 255],
@@ -1,5 +1,7 @@
 use enum_dispatch::enum_dispatch;
 
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
 #[enum_dispatch(MemoryType)]
 pub trait Memory {
     fn read(&self, addr: u16) -> u8;
@@ -7,10 +9,12 @@ pub trait Memory {
 }
 
 #[enum_dispatch]
+#[derive(Clone)]
 pub enum MemoryType {
     DMGMemory,
 }
 
+#[derive(Clone)]
 pub struct DMGMemory {
     wram: [u8; 8192],
     high_ram: [u8; 512],
@@ -22,24 +26,55 @@ impl DMGMemory {
     }
 }
 
+impl Default for DMGMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for MemoryType {
+    fn save_state(&self, w: &mut StateWriter) {
+        match self {
+            MemoryType::DMGMemory(m) => m.save_state(w),
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        match self {
+            MemoryType::DMGMemory(m) => m.load_state(r),
+        }
+    }
+}
+
+impl Savestate for DMGMemory {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.wram);
+        w.write_bytes(&self.high_ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_bytes(&mut self.wram)?;
+        r.read_bytes(&mut self.high_ram)
+    }
+}
+
 impl Memory for DMGMemory {
     fn read(&self, addr: u16) -> u8 {
-        if addr >= 0xC000 && addr < 0xE000 {
+        if (0xC000..0xE000).contains(&addr) {
             self.wram[addr as usize - 0xC000]
         } else if addr >= 0xFF80 {
             self.high_ram[addr as usize - 0xFF80]
         } else {
-            panic!("invalid DMGMemory read address: {}", addr);
+            // Not work or high RAM: nothing here drives the bus
+            0xFF
         }
     }
 
     fn write(&mut self, addr: u16, value: u8) {
-        if addr >= 0xC000 && addr < 0xE000 {
+        if (0xC000..0xE000).contains(&addr) {
             self.wram[addr as usize - 0xC000] = value;
         } else if addr >= 0xFF80 {
             self.high_ram[addr as usize - 0xFF80] = value;
-        } else {
-            panic!("invalid DMGMemory read address: {}", addr);
         }
     }
 }
@@ -1,24 +1,127 @@
+use crate::apu::Apu;
 use crate::lcd::Lcd;
 use crate::memory::{MemoryType, Memory};
 use crate::cartridge::{CartridgeType, Cartridge};
+use crate::dma::OamDma;
+use crate::interrupts::{Interrupt, Interrupts};
+use crate::joypad::Joypad;
 use crate::ppu::Ppu;
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+use crate::serial::Serial;
+use crate::timers::Timers;
 
+#[derive(Clone)]
 pub struct Mmu {
     ppu: Ppu,
+    apu: Apu,
+    serial: Serial,
     memory: MemoryType,
     cartridge: CartridgeType,
+    timers: Timers,
+    lcd: Lcd,
+    interrupts: Interrupts,
+    joypad: Joypad,
+    dma: OamDma,
 }
 
 impl Mmu {
     pub fn new(memory: MemoryType, cartridge: CartridgeType) -> Self {
-        Mmu {ppu: Ppu::new(), memory, cartridge}
+        Mmu {ppu: Ppu::new(), apu: Apu::default(), serial: Serial::new(), memory, cartridge, timers: Timers::new(), lcd: Lcd::new(), interrupts: Interrupts::new(), joypad: Joypad::new(), dma: OamDma::new()}
     }
 
-    pub fn tick(&mut self, lcd: &mut Lcd, m_cycles: u8) {
-        self.ppu.tick(lcd, m_cycles);
+    pub fn tick(&mut self, m_cycles: u8) {
+        // Advance everything on the bus. The CPU calls this as it goes, once for every m-cycle it spends.
+        if self.timers.tick(m_cycles) {
+            self.interrupts.request(Interrupt::Timer);
+        }
+        self.ppu.tick(&mut self.lcd, &mut self.interrupts, m_cycles);
+        self.apu.tick(m_cycles);
+        if self.serial.tick(m_cycles) {
+            self.interrupts.request(Interrupt::Serial);
+        }
+        self.cartridge.tick(m_cycles);
+        if self.joypad.take_interrupt() {
+            self.interrupts.request(Interrupt::Joypad);
+        }
+        for _ in 0..m_cycles {
+            self.tick_dma();
+        }
+    }
+
+    fn tick_dma(&mut self) {
+        // OAM DMA copies one byte per m-cycle, straight into OAM whatever the PPU is doing
+        if let Some((source, offset)) = self.dma.tick() {
+            let value = self.read_dma_source(source);
+            self.dma.set_value(value);
+            self.ppu.write_oam_dma(offset, value);
+        }
+    }
+
+    pub fn reset_div(&mut self) {
+        self.timers.reset_div();
+    }
+
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
+    pub fn lcd(&self) -> &Lcd {
+        &self.lcd
+    }
+
+    pub fn take_frame_complete(&mut self) -> bool {
+        self.ppu.take_frame_complete()
+    }
+
+    pub fn interrupts(&self) -> &Interrupts {
+        &self.interrupts
+    }
+
+    pub fn interrupts_mut(&mut self) -> &mut Interrupts {
+        &mut self.interrupts
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        &self.joypad
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.serial
+    }
+
+    pub fn cartridge(&self) -> &CartridgeType {
+        &self.cartridge
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut CartridgeType {
+        &mut self.cartridge
     }
 
     pub fn read(&self, addr: u16) -> u8 {
+        // While OAM DMA is running, OAM and the bus it reads from are out of the CPU's reach
+        if let Some(value) = self.dma.conflict(addr) {
+            return value;
+        }
         match addr {
             0x0000..=0x7FFF => self.cartridge.read(addr),        // Cartridge ROM
             0x8000..=0x9FFF => self.ppu.read(addr),              // Video RAM
@@ -27,12 +130,16 @@ impl Mmu {
             0xE000..=0xFDFF => self.memory.read(addr - 0x2000),  // Echo RAM
             0xFE00..=0xFE9F => self.ppu.read(addr),              // OAM
             0xFEA0..=0xFEFF => 0xFF,                             // Forbidden Memory
-            0xFF00..=0xFF7F => self.ppu.read(addr),              // IO Regs
-            0xFF80.. => self.memory.read(addr)                   // High RAM, Interrupt Enable Register
+            0xFF00..=0xFF7F => self.read_io(addr),               // IO Regs
+            0xFF80..=0xFFFE => self.memory.read(addr),           // High RAM
+            0xFFFF => self.interrupts.read(addr),                // Interrupt Enable
         }
     }
 
     pub fn write(&mut self, addr: u16, value: u8) {
+        if self.dma.conflict(addr).is_some() {
+            return;
+        }
         match addr {
             0x0000..=0x7FFF => self.cartridge.write(addr, value),       // Cartridge ROM
             0x8000..=0x9FFF => self.ppu.write(addr, value),             // Video RAM
@@ -41,27 +148,227 @@ impl Mmu {
             0xE000..=0xFDFF => self.memory.write(addr - 0x2000, value), // Echo RAM
             0xFE00..=0xFE9F => self.ppu.write(addr, value),             // OAM
             0xFEA0..=0xFEFF => (),                                      // Forbidden Memory
-            0xFF00..=0xFF7F => {                                        // IO Regs
+            0xFF00..=0xFF7F => self.write_io(addr, value),              // IO Regs
+            0xFF80..=0xFFFE => self.memory.write(addr, value),          // High RAM
+            0xFFFF => self.interrupts.write(addr, value),               // Interrupt Enable
+        }
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        // Every IO register is owned by one component. Registers that don't exist on the DMG aren't driven by anything,
+        // so they read as 0xFF, as do the unused bits of the ones that do exist.
+        match addr {
+            0xFF00 => self.joypad.read(),                        // Joypad
+            0xFF01..=0xFF02 => self.serial.read(addr),           // Serial
+            0xFF04..=0xFF07 => self.timers.read(addr),           // Timers
+            0xFF0F => self.interrupts.read(addr),                // Interrupt Flag
+            0xFF10..=0xFF3F => self.apu.read(addr),              // Sound
+            0xFF41 => self.ppu.read(addr) | 0x80,                // STAT, bit 7 is unused
+            0xFF46 => self.dma.read(),                           // OAM DMA
+            0xFF40..=0xFF4B => self.ppu.read(addr),              // LCD
+            _ => 0xFF,
+        }
+    }
+
+    fn write_io(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF00 => self.joypad.write(value),                         // Joypad
+            0xFF01..=0xFF02 => self.serial.write(addr, value),          // Serial
+            0xFF04..=0xFF07 => self.timers.write(addr, value),          // Timers
+            0xFF0F => self.interrupts.write(addr, value),               // Interrupt Flag
+            0xFF10..=0xFF3F => self.apu.write(addr, value),             // Sound
+            0xFF41 => {                                                 // STAT, the mode and LYC=LY bits are read-only
+                let stat = self.ppu.read(addr);
+                self.ppu.write(addr, (value & 0x78) | (stat & 0x07));
+            }
+            0xFF40 if value & 0x80 == 0 => {                            // LCDC, the screen is blank while the LCD is off
                 self.ppu.write(addr, value);
-                if addr == 0xFF46 {
-                    let mut data: [u8; 160] = [0; 160];
-                    let value_base = (value as u16) << 8;
-                    for i in 0x00..=0x9F {
-                        data[i as usize] = self.read(value_base | i);
-                    }
-                    self.ppu.dma(&data);
-                }
+                self.lcd.clear();
             }
-            0xFF80.. => self.memory.write(addr, value)                  // High RAM, Interrupt Enable Register
+            0xFF44 => (),                                               // LY is read-only
+            0xFF46 => self.dma.start(value),                            // OAM DMA
+            0xFF40..=0xFF4B => self.ppu.write(addr, value),             // LCD
+            _ => (),
+        }
+    }
+
+    fn read_dma_source(&self, addr: u16) -> u8 {
+        // OAM DMA has its own path to VRAM, which isn't blocked while the PPU is drawing.
+        // Sources above work RAM have already been mapped onto it.
+        match addr {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.cartridge.read(addr),
+            0x8000..=0x9FFF => self.ppu.peek(addr),
+            _ => self.memory.read(addr),
         }
     }
 
     pub fn read_word(&self, addr: u16) -> u16 {
-        ((self.read(addr + 1) as u16) << 8) | (self.read(addr) as u16)
+        ((self.read(addr.wrapping_add(1)) as u16) << 8) | (self.read(addr) as u16)
     }
 
     pub fn write_word(&mut self, addr: u16, value: u16) {
-        self.write(addr + 1, (value >> 8) as u8);
+        self.write(addr.wrapping_add(1), (value >> 8) as u8);
         self.write(addr, (value & 0xFF) as u8);
     }
 }
+
+impl Savestate for Mmu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.memory.save_state(w);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        self.serial.save_state(w);
+        self.cartridge.save_state(w);
+        self.timers.save_state(w);
+        self.lcd.save_state(w);
+        self.interrupts.save_state(w);
+        self.joypad.save_state(w);
+        self.dma.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.memory.load_state(r)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.cartridge.load_state(r)?;
+        self.timers.load_state(r)?;
+        self.lcd.load_state(r)?;
+        self.interrupts.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.dma.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::load_cartridge;
+    use crate::header::CartridgeHeader;
+    use crate::memory::DMGMemory;
+
+    fn setup() -> Mmu {
+        let rom = [0; 0x8000];
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        Mmu::new(MemoryType::DMGMemory(DMGMemory::new()), load_cartridge(&rom, &header).unwrap())
+    }
+
+    #[test]
+    fn unmapped_io_registers_read_as_open_bus() {
+        let mut mmu = setup();
+        for addr in [0xFF03, 0xFF08, 0xFF4C, 0xFF50, 0xFF7F] {
+            mmu.write(addr, 0x00);
+            assert_eq!(mmu.read(addr), 0xFF);
+        }
+        // Only the select bits of P1 can be written, the unused ones read as 1
+        mmu.write(0xFF00, 0x00);
+        assert_eq!(mmu.read(0xFF00), 0xCF);
+    }
+
+    #[test]
+    fn read_only_bits_are_enforced() {
+        let mut mmu = setup();
+        mmu.tick(1);
+        let stat = mmu.read(0xFF41);
+        mmu.write(0xFF41, 0xFF);
+        assert_eq!(mmu.read(0xFF41), 0xF8 | (stat & 0x07));
+
+        for _ in 0..200 {
+            mmu.tick(1);
+        }
+        let ly = mmu.read(0xFF44);
+        assert_ne!(ly, 0);
+        mmu.write(0xFF44, 0x00);
+        assert_eq!(mmu.read(0xFF44), ly);
+
+        mmu.write(0xFF04, 0x12);
+        assert_eq!(mmu.read(0xFF04), 0);
+    }
+
+    // An MMU with the LCD off, so OAM is only ever blocked by DMA, and a pattern in work RAM to copy
+    fn setup_dma() -> Mmu {
+        let mut mmu = setup();
+        mmu.write(0xFF40, 0x00);
+        for i in 0..0xA0 {
+            mmu.write(0xC000 + i, i as u8 ^ 0x5A);
+        }
+        mmu
+    }
+
+    #[test]
+    fn oam_dma_takes_160_cycles_after_a_delay() {
+        let mut mmu = setup_dma();
+        mmu.write(0xFF46, 0xC0);
+        assert_eq!(mmu.read(0xFF46), 0xC0);
+
+        // Start-up delay, OAM is still accessible
+        mmu.tick(1);
+        assert_eq!(mmu.read(0xFE00), 0x00);
+
+        mmu.tick(1);
+        assert_eq!(mmu.read(0xFE00), 0xFF);
+        mmu.write(0xFE10, 0x12);
+        for _ in 1..160 {
+            mmu.tick(1);
+            assert_eq!(mmu.read(0xFE00), 0xFF);
+        }
+
+        mmu.tick(1);
+        for i in 0..0xA0 {
+            assert_eq!(mmu.read(0xFE00 + i), i as u8 ^ 0x5A);
+        }
+    }
+
+    #[test]
+    fn oam_dma_restart_keeps_oam_blocked() {
+        let mut mmu = setup_dma();
+        mmu.write(0xFF46, 0xC0);
+        for _ in 0..10 {
+            mmu.tick(1);
+        }
+        // The old transfer carries on through the new one's start-up delay
+        mmu.write(0xFF46, 0xC0);
+        for _ in 0..161 {
+            mmu.tick(1);
+            assert_eq!(mmu.read(0xFE00), 0xFF);
+        }
+        mmu.tick(1);
+        assert_eq!(mmu.read(0xFE00), 0x5A);
+    }
+
+    #[test]
+    fn oam_dma_conflicts_with_its_source_bus() {
+        let mut mmu = setup_dma();
+        mmu.write(0xFF80, 0x42);
+        mmu.write(0xFF46, 0xC0);
+        mmu.tick(2);
+
+        // The external bus returns whatever the DMA just read, and ignores writes
+        assert_eq!(mmu.read(0xD000), 0x5A);
+        assert_eq!(mmu.read(0x0100), 0x5A);
+        mmu.write(0xC000, 0x00);
+        // VRAM and HRAM are on other buses
+        assert_eq!(mmu.read(0x8000), 0x00);
+        assert_eq!(mmu.read(0xFF80), 0x42);
+
+        for _ in 0..160 {
+            mmu.tick(1);
+        }
+        assert_eq!(mmu.read(0xC000), 0x5A);
+    }
+
+    #[test]
+    fn oam_dma_state_with_an_impossible_source_is_rejected() {
+        let mut mmu = setup_dma();
+        mmu.write(0xFF46, 0xC0);
+        mmu.tick(10);
+        let mut w = StateWriter::new();
+        mmu.dma.save_state(&mut w);
+        let mut data = w.into_bytes();
+        assert!(OamDma::new().load_state(&mut StateReader::new(&data)).is_ok());
+
+        // The active transfer's source: after the register, and the starting transfer's flag, source and delay
+        data[1 + 4 + 1..1 + 4 + 3].copy_from_slice(&0xFFF0u16.to_le_bytes());
+        assert!(matches!(OamDma::new().load_state(&mut StateReader::new(&data)), Err(SavestateError::InvalidFormat)));
+    }
+}
@@ -1,39 +1,195 @@
 use crate::cpu::Cpu;
-use crate::cartridge::{self};
-use crate::joypad::{self, Joypad};
+use crate::error::EmulatorError;
+use crate::cartridge::{self, Cartridge};
+use crate::header::{CartridgeError, CartridgeHeader};
+use crate::joypad::Joypad;
 use crate::lcd::Lcd;
 use crate::memory::{MemoryType, DMGMemory};
 use crate::mmu::Mmu;
+use crate::ppu::{AccessMode, RenderMode};
+use crate::savestate::{self, RomId, Savestate, SavestateError, StateReader, StateWriter};
+use crate::serial::SerialDevice;
 use crate::timers::Timers;
-use crate::ppu::Ppu;
+
+// A full frame is 154 scanlines of 456 t-cycles each, or 17556 m-cycles
+pub const MCYCLES_PER_FRAME: u32 = 17556;
 
 pub struct Motherboard {
     pub cpu: Cpu,
     pub mmu: Mmu,
-    pub timers: Timers,
-    pub lcd: Lcd,
-    pub joypad: Joypad,
+
+    header: CartridgeHeader,
+    rom_id: RomId,
 }
 
 impl Motherboard {
-    pub fn new(cart_rom: &Vec<u8>) -> Self {
-        Motherboard {
+    pub fn new(cart_rom: &[u8]) -> Result<Self, CartridgeError> {
+        let header = CartridgeHeader::parse(cart_rom)?;
+        Ok(Motherboard {
             cpu: Cpu::new(),
             mmu: Mmu::new(
                 MemoryType::DMGMemory(DMGMemory::new()),
-                cartridge::load_cartridge(cart_rom),
+                cartridge::load_cartridge(cart_rom, &header)?,
             ),
-            timers: Timers::new(),
-            lcd: Lcd::new(),
-            joypad: Joypad::new(),
+
+            header,
+            rom_id: RomId::new(cart_rom),
+        })
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn tick(&mut self) -> Result<u8, EmulatorError> {
+        if self.cpu.stopped {
+            return Ok(self.tick_stopped());
+        }
+
+        // The CPU advances the rest of the system itself, cycle by cycle, as it executes
+        let mcycles = self.cpu.execute(&mut self.mmu)?;
+        if self.cpu.stopped {
+            self.enter_stop();
+        }
+        Ok(mcycles)
+    }
+
+    fn enter_stop(&mut self) {
+        // STOP resets DIV. On the CGB, this is also where an armed speed switch would take place
+        // (after which the CPU carries on right away instead of staying in low-power mode).
+        self.mmu.reset_div();
+    }
+
+    fn tick_stopped(&mut self) -> u8 {
+        // In low-power mode the system clock is stopped: the PPU, APU and timers all stand still,
+        // and the LCD keeps showing whatever it showed last. Only the cartridge's RTC has its own clock.
+        self.mmu.cartridge_mut().tick(1);
+
+        // Any selected joypad line going low (i.e. a button being pressed) wakes the CPU up again
+        if self.mmu.read(0xFF00) & 0x0F != 0x0F {
+            self.cpu.stopped = false;
         }
+        1
+    }
+
+    pub fn run_frame(&mut self) -> Result<&Lcd, EmulatorError> {
+        // Run the emulator until the PPU finishes a frame (enters VBlank), then return the finished image.
+        // If no frame is finished within a frame's worth of cycles (e.g. the LCD is disabled), stop there anyway.
+        let mut cycles: u32 = 0;
+        while cycles < MCYCLES_PER_FRAME {
+            cycles += self.tick()? as u32;
+            if self.mmu.take_frame_complete() {
+                break;
+            }
+        }
+        Ok(self.mmu.lcd())
+    }
+
+    pub fn timers(&self) -> &Timers {
+        self.mmu.timers()
+    }
+
+    pub fn lcd(&self) -> &Lcd {
+        // The image currently on screen
+        self.mmu.lcd()
+    }
+
+    pub fn joypad(&self) -> &Joypad {
+        self.mmu.joypad()
+    }
+
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
+        // Press and release buttons through this
+        self.mmu.joypad_mut()
     }
 
-    pub fn tick(&mut self) -> u8 {
-        self.joypad.tick(&mut self.mmu);
-        let mcycles = self.cpu.execute(&mut self.mmu);
-        self.timers.tick(&mut self.mmu, mcycles);
-        self.mmu.tick(&mut self.lcd, mcycles);
-        mcycles
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        // Connect something to the link cable port
+        self.mmu.serial_mut().set_device(device);
     }
-}
\ No newline at end of file
+
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        // Interleaved stereo (left, right) samples generated since the last call
+        self.mmu.apu_mut().take_samples()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mmu.apu_mut().set_sample_rate(sample_rate);
+    }
+
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        // The FIFO renderer gets mid-line raster effects right, at the cost of speed
+        self.mmu.ppu_mut().set_render_mode(mode);
+    }
+
+    pub fn set_access_mode(&mut self, mode: AccessMode) {
+        // Lenient access lets ROMs that touch VRAM or OAM while the PPU is using them run anyway
+        self.mmu.ppu_mut().set_access_mode(mode);
+    }
+
+    pub fn cpu_locked(&self) -> bool {
+        // Whether the CPU has hung on an illegal opcode. Only a reset (or loading a save state) gets it going again.
+        self.cpu.locked
+    }
+
+    pub fn rumble(&self) -> bool {
+        // Whether the cartridge is currently asking for its rumble motor to be on
+        self.mmu.cartridge().rumble()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        // Snapshot the complete machine state, tagged with the ROM it belongs to
+        let mut w = StateWriter::new();
+        savestate::write_header(&mut w, &self.rom_id);
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SavestateError> {
+        let mut r = StateReader::new(data);
+        savestate::read_header(&mut r, &self.rom_id)?;
+
+        // Load into a copy of the machine, so a state that turns out to be corrupt part way through
+        // leaves the running one untouched
+        let mut cpu = self.cpu.clone();
+        let mut mmu = self.mmu.clone();
+        cpu.load_state(&mut r)?;
+        mmu.load_state(&mut r)?;
+        r.finish()?;
+
+        // Whatever is plugged into the link port stays plugged in
+        mmu.serial_mut().set_device(self.mmu.serial_mut().take_device());
+        self.cpu = cpu;
+        self.mmu = mmu;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::CaptureSerial;
+
+    #[test]
+    fn loading_a_state_keeps_the_machine_and_its_link_cable() {
+        let mut mb = Motherboard::new(&vec![0; 0x8000]).unwrap();
+        let serial = CaptureSerial::new();
+        mb.set_serial_device(Box::new(serial.clone()));
+        let state = mb.save_state();
+        for _ in 0..100 {
+            mb.tick().unwrap();
+        }
+
+        // A state cut short is only noticed at the end, and leaves the running machine as it was
+        let before = mb.save_state();
+        assert!(mb.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(mb.save_state(), before);
+
+        mb.load_state(&state).unwrap();
+        assert_eq!(mb.save_state(), state);
+        mb.mmu.write(0xFF01, 0x42);
+        mb.mmu.write(0xFF02, 0x81);
+        assert_eq!(serial.output(), vec![0x42]);
+    }
+}
@@ -1,6 +1,28 @@
-use crate::{lcd::Lcd};
+use crate::fifo::{self, FifoRenderer};
+use crate::interrupts::{Interrupt, Interrupts};
+use crate::lcd::{Lcd, LCD_HEIGHT};
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+// How mode 3 is emulated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    // Each line is drawn all at once at the start of mode 3, whose length is estimated from the scroll, window and objects. Fast.
+    Scanline,
+    // Each line is drawn dot by dot through the pixel FIFO, and mode 3 takes as long as on hardware
+    Fifo,
+}
+
+// Whether the CPU is kept out of VRAM and OAM while the PPU is using them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessMode {
+    // Like on hardware: VRAM can't be accessed during mode 3, OAM not during modes 2 and 3.
+    // Blocked reads return 0xFF and blocked writes are dropped.
+    Strict,
+    // VRAM and OAM can always be accessed, for ROMs that (wrongly) rely on it
+    Lenient,
+}
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 enum PpuMode {
     HBlank,
     VBlank,
@@ -8,6 +30,7 @@ enum PpuMode {
     Drawing,
 }
 
+#[derive(Clone)]
 pub struct Ppu {
     vram: [u8; 8192],
     oam: [u8; 160],
@@ -15,8 +38,20 @@ pub struct Ppu {
 
     mode: PpuMode,
     line_cycles: u32,
+    // Dot of the line at which the scanline renderer ends mode 3
+    mode_3_end: u32,
     reached_window: bool,
     window_line_counter: u16,
+    frame_complete: bool,
+    // The first line after the LCD is turned on skips OAM scan, and the first frame isn't shown
+    first_line: bool,
+    skip_frame: bool,
+
+    render_mode: RenderMode,
+    access_mode: AccessMode,
+    fifo: FifoRenderer,
+    // Whether the current line is being drawn by the FIFO renderer
+    fifo_line: bool,
 }
 
 impl Ppu {
@@ -28,8 +63,17 @@ impl Ppu {
 
             mode: PpuMode::OAMScan,
             line_cycles: 0,
+            mode_3_end: 252,
             reached_window: false,
             window_line_counter: 0,
+            frame_complete: false,
+            first_line: false,
+            skip_frame: false,
+
+            render_mode: RenderMode::Scanline,
+            access_mode: AccessMode::Strict,
+            fifo: FifoRenderer::new(),
+            fifo_line: false,
         };
         ppu.io_regs[0x0040] = 0x85;
         ppu.io_regs[0x0042] = 0;
@@ -41,88 +85,242 @@ impl Ppu {
 
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            // 0x8000..=0x9FFF => if self.mode != PpuMode::Drawing { self.vram[(addr - 0x8000) as usize] } else { 0xFF },
-            // 0xFE00..=0xFE9F => if self.mode == PpuMode::HBlank || self.mode == PpuMode::VBlank { self.oam[(addr - 0xFE00) as usize] } else { 0xFF }, 
+            0x8000..=0x9FFF if !self.vram_accessible() => 0xFF,
+            0xFE00..=0xFE9F if !self.oam_accessible() => 0xFF,
+            _ => self.peek(addr),
+        }
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        // Reads without any access blocking, for OAM DMA (and debugging)
+        match addr {
             0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize],
             0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize],
             0xFF00..=0xFF7F => self.io_regs[(addr - 0xFF00) as usize],
-            _ => panic!("invalid memory read on ppu: {}", addr)
+            _ => 0xFF,
         }
     }
 
     pub fn write(&mut self, addr: u16, value: u8) {
         match addr {
-            // VRAM/OAM blocking temporarily disabled
-            // 0x8000..=0x9FFF => if self.mode != PpuMode::Drawing { self.vram[(addr - 0x8000) as usize] = value; },
-            // 0xFE00..=0xFE9F => if self.mode == PpuMode::HBlank || self.mode == PpuMode::VBlank { self.oam[(addr - 0xFE00) as usize] = value; }
-            0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize] = value,
-            0xFE00..=0xFE9F => self.oam[(addr - 0xFE00) as usize] = value,
+            0x8000..=0x9FFF if self.vram_accessible() => self.vram[(addr - 0x8000) as usize] = value,
+            0xFE00..=0xFE9F if self.oam_accessible() => self.oam[(addr - 0xFE00) as usize] = value,
+            0xFF40 => {
+                let was_enabled = self.lcd_enabled();
+                self.io_regs[0x0040] = value;
+                match (was_enabled, self.lcd_enabled()) {
+                    (true, false) => self.disable_lcd(),
+                    (false, true) => self.enable_lcd(),
+                    _ => (),
+                }
+            }
             0xFF00..=0xFF7F => self.io_regs[(addr - 0xFF00) as usize] = value,
-            _ => panic!("invalid memory write on ppu: {}", addr)
+            _ => (),
         }
     }
 
-    pub fn dma(&mut self, data: &[u8]) {
-        self.oam.copy_from_slice(data);
+    pub fn set_access_mode(&mut self, mode: AccessMode) {
+        self.access_mode = mode;
     }
 
-    pub fn tick(&mut self, lcd: &mut Lcd, m_cycles: u8) {
-        let t_cycles = m_cycles * 4;
-        self.line_cycles += t_cycles as u32;
+    fn lcd_enabled(&self) -> bool {
+        self.io_regs[0x0040] & 0b10000000 != 0
+    }
 
-        // Read current LY/LYC register value
-        let ly = self.io_regs[0x0044];
-        let lyc = self.io_regs[0x0045];
+    fn disable_lcd(&mut self) {
+        // With the LCD off the PPU's clock stops, with LY at 0 and STAT in mode 0
+        self.io_regs[0x0044] = 0;
+        self.mode = PpuMode::HBlank;
+        self.line_cycles = 0;
+        self.reached_window = false;
+        self.window_line_counter = 0;
+        self.fifo_line = false;
+        self.update_stat();
+    }
 
-        let stat = self.io_regs[0x0041];
+    fn enable_lcd(&mut self) {
+        // Turning the LCD back on starts at line 0, which is 4 dots short and stays in mode 0 instead of scanning OAM.
+        // The frame that follows isn't shown, the screen stays blank until the next one.
+        self.line_cycles = 4;
+        self.first_line = true;
+        self.skip_frame = true;
+        self.reached_window = self.io_regs[0x004A] == 0;
+        self.update_stat();
+    }
 
-        // Check for going to the next scanline
-        if self.line_cycles >= 456 {
-            // Write new LY register value
-            let new_ly = (ly + 1) % 154;
+    fn vram_accessible(&self) -> bool {
+        // The PPU only reads VRAM while drawing. With the LCD off, it doesn't use VRAM or OAM at all.
+        self.access_mode == AccessMode::Lenient || !self.lcd_enabled() || self.mode != PpuMode::Drawing
+    }
 
-            // Indicate when window reached
-            if new_ly == self.io_regs[0x004A] { self.reached_window = true; }
+    fn oam_accessible(&self) -> bool {
+        self.access_mode == AccessMode::Lenient || !self.lcd_enabled()
+            || self.mode == PpuMode::HBlank || self.mode == PpuMode::VBlank
+    }
 
-            self.io_regs[0x0044] = new_ly;
+    pub fn write_oam_dma(&mut self, offset: u8, value: u8) {
+        // OAM DMA writes OAM directly, even while the PPU has it locked
+        self.oam[offset as usize] = value;
+    }
 
-            // If LYC=LY and the LYC=LY STAT interrupt source is set, request a STAT interrupt
-            if (new_ly == lyc) && (stat & 0b01000000 != 0) {
-                self.req_stat_interrupt();
+    pub fn take_frame_complete(&mut self) -> bool {
+        // Returns true once after the PPU has finished drawing a frame (entered VBlank)
+        std::mem::replace(&mut self.frame_complete, false)
+    }
+
+    pub fn set_render_mode(&mut self, mode: RenderMode) {
+        // Takes effect from the next line on: a line the FIFO renderer has started is finished by it
+        self.render_mode = mode;
+    }
+
+    pub fn tick(&mut self, lcd: &mut Lcd, interrupts: &mut Interrupts, m_cycles: u8) {
+        if !self.lcd_enabled() {
+            return;
+        }
+        if self.render_mode == RenderMode::Fifo || (self.fifo_line && self.mode == PpuMode::Drawing) {
+            for _ in 0..m_cycles as u32 * 4 {
+                self.tick_dot(lcd, interrupts);
             }
+        } else {
+            self.tick_scanline(lcd, interrupts, m_cycles);
+        }
+    }
 
+    fn tick_scanline(&mut self, lcd: &mut Lcd, interrupts: &mut Interrupts, m_cycles: u8) {
+        self.line_cycles += m_cycles as u32 * 4;
+
+        // Check for going to the next scanline
+        if self.line_cycles >= 456 {
             self.line_cycles -= 456;
-            self.mode = if new_ly >= 144 {
-                if self.mode != PpuMode::VBlank {
-                    if (stat & 0b00010000) != 0 { self.req_stat_interrupt(); }
-                    self.req_vblank_interrupt();
-                    self.reached_window = false;
-                    self.window_line_counter = 0;
-                }
-                PpuMode::VBlank 
-            } else {
-                if (stat & 0b00100000) != 0 { self.req_stat_interrupt(); }
-                PpuMode::OAMScan 
-            };
+            self.next_line(interrupts);
         }
 
         // Check for OAM Scan -> Drawing mode switch
-        if self.line_cycles >= 80 && self.mode == PpuMode::OAMScan {
+        if self.line_cycles >= 80 && (self.mode == PpuMode::OAMScan || self.first_line) {
             // Push a row of pixels to the LCD (all at once, at start of mode)
-            self.draw_line(lcd, ly, self.io_regs[0x0040]);
+            self.draw_line(lcd, self.io_regs[0x0044], self.io_regs[0x0040]);
+            self.mode_3_end = 80 + self.mode_3_length();
             self.mode = PpuMode::Drawing;
+            self.first_line = false;
+            self.fifo_line = false;
         }
 
         // Check for Drawing -> HBlank mode switch
-        // Normally this would be a variable number of cycles,
-        // but it doesn't really matter.
-        if self.line_cycles >= 252 && self.mode == PpuMode::Drawing {
-            if (stat & 0b00001000) != 0 { self.req_stat_interrupt(); }
-            self.mode = PpuMode::HBlank;
+        if self.line_cycles >= self.mode_3_end && self.mode == PpuMode::Drawing {
+            self.enter_hblank(interrupts);
         }
 
+        self.update_stat();
+    }
+
+    fn tick_dot(&mut self, lcd: &mut Lcd, interrupts: &mut Interrupts) {
+        self.line_cycles += 1;
+
+        if self.line_cycles == 456 {
+            self.line_cycles = 0;
+            self.next_line(interrupts);
+        }
+
+        if self.line_cycles == 80 && (self.mode == PpuMode::OAMScan || self.first_line) {
+            self.fifo.start_line(&self.oam, &self.io_regs[..0x100], self.reached_window, self.window_line_counter);
+            self.mode = PpuMode::Drawing;
+            self.first_line = false;
+            self.fifo_line = true;
+        }
+
+        if self.mode == PpuMode::Drawing {
+            // Mode 3 lasts until the renderer has output the whole line, which takes a variable number of dots.
+            // A line started by the scanline renderer (before switching modes) still ends where it estimated.
+            let done = if self.fifo_line {
+                self.fifo.dot(&self.vram, &self.io_regs[..0x100])
+            } else {
+                self.line_cycles >= self.mode_3_end
+            };
+
+            if done {
+                if self.fifo_line {
+                    if !self.skip_frame { lcd.set_line(self.io_regs[0x0044], *self.fifo.line()); }
+                    if self.fifo.window_drawn() { self.window_line_counter += 1; }
+                }
+                self.enter_hblank(interrupts);
+            }
+        }
+
+        self.update_stat();
+    }
+
+    fn mode_3_length(&self) -> u32 {
+        // Estimate of how many dots the FIFO renderer would spend on this line (following Pan Docs):
+        // 172, plus the pixels discarded for fine scrolling, plus 6 for restarting the fetcher at the window,
+        // plus 6 for every object, and up to 5 more for the first object over each background tile
+        let lcdc = self.io_regs[0x0040];
+        let scx = self.io_regs[0x0043];
+        let mut dots = 172 + (scx % 8) as u32;
+
+        let wx = self.io_regs[0x004B];
+        if lcdc & 0b00100000 != 0 && self.reached_window && wx <= 166 {
+            dots += 6;
+        }
+
+        if lcdc & 0b00000010 != 0 {
+            // Background tiles already stalled for, by position on the line (including the 8 pixels left of it)
+            let mut tiles: u64 = 0;
+            for entry in fifo::line_objects(&self.oam, &self.io_regs[..0x100]) {
+                let x = entry[1];
+                if x >= 168 { continue; }
+                dots += 6;
+                let pos = x as u32 + (scx % 8) as u32;
+                if tiles & (1 << (pos / 8)) == 0 {
+                    tiles |= 1 << (pos / 8);
+                    dots += 5 - (pos % 8).min(5);
+                }
+            }
+        }
+        dots
+    }
+
+    fn next_line(&mut self, interrupts: &mut Interrupts) {
+        let stat = self.io_regs[0x0041];
+        let lyc = self.io_regs[0x0045];
+
+        // Write new LY register value
+        let new_ly = (self.io_regs[0x0044] + 1) % 154;
+
+        // Indicate when window reached
+        if new_ly == self.io_regs[0x004A] { self.reached_window = true; }
+
+        self.io_regs[0x0044] = new_ly;
+
+        // If LYC=LY and the LYC=LY STAT interrupt source is set, request a STAT interrupt
+        if (new_ly == lyc) && (stat & 0b01000000 != 0) {
+            interrupts.request(Interrupt::Stat);
+        }
+
+        self.mode = if new_ly >= 144 {
+            if self.mode != PpuMode::VBlank {
+                if (stat & 0b00010000) != 0 { interrupts.request(Interrupt::Stat); }
+                interrupts.request(Interrupt::VBlank);
+                self.frame_complete = true;
+                self.skip_frame = false;
+                self.reached_window = false;
+                self.window_line_counter = 0;
+            }
+            PpuMode::VBlank
+        } else {
+            if (stat & 0b00100000) != 0 { interrupts.request(Interrupt::Stat); }
+            PpuMode::OAMScan
+        };
+    }
+
+    fn enter_hblank(&mut self, interrupts: &mut Interrupts) {
+        if (self.io_regs[0x0041] & 0b00001000) != 0 { interrupts.request(Interrupt::Stat); }
+        self.mode = PpuMode::HBlank;
+    }
+
+    fn update_stat(&mut self) {
+        let stat = self.io_regs[0x0041];
         // Reset LYC=LY flag in STAT register
-        let mut new_stat = if ly == lyc { stat | 0b00000100 } else { stat & 0b11111011 };
+        let mut new_stat = if self.io_regs[0x0044] == self.io_regs[0x0045] { stat | 0b00000100 } else { stat & 0b11111011 };
         // Set ppu mode in STAT register bits 0-1
         new_stat &= 0b11111100;
         new_stat |= match self.mode {
@@ -134,16 +332,10 @@ impl Ppu {
         self.io_regs[0x0041] = new_stat;
     }
 
-    fn req_vblank_interrupt(&mut self) {
-        self.io_regs[0x000F] = self.io_regs[0x000F] | 0b00000001;
-    }
-
-    fn req_stat_interrupt(&mut self) {
-        self.io_regs[0x000F] = self.io_regs[0x000F] | 0b00000010;
-    }
-
     fn draw_line(&mut self, lcd: &mut Lcd, ly: u8, lcdc: u8) {
         let mut line: [u8; 160] = [0; 160];
+        // Background and window color indices (before the palette), which decide whether objects behind them show
+        let mut bg_line: [u8; 160] = [0; 160];
 
         // Background and Window only drawn if bit 0 of LCDC is set
         if lcdc & 0b00000001 != 0 {
@@ -175,11 +367,12 @@ impl Ppu {
                 let b2 = self.vram[tile_addr + 1];
 
                 for px in 0..8 {
-                    if (x_counter * 8 + px) > (scx % 8) as u16 {
+                    if (x_counter * 8 + px) >= (scx % 8) as u16 {
                         let linepos = (x_counter * 8 + px - (scx % 8) as u16) as usize;
                         if linepos < 160 {
-                            let px_val = if b1 & (1 << 7 - px) != 0 { 1 } else { 0 } | if b2 & (1 << 7 - px) != 0 { 2 } else { 0 };
+                            let px_val = if b1 & (1 << (7 - px)) != 0 { 1 } else { 0 } | if b2 & (1 << (7 - px)) != 0 { 2 } else { 0 };
                             let color = (bg_palette >> (px_val * 2)) & 0x3;
+                            bg_line[linepos] = px_val;
                             line[linepos] = color;
                         }
                     }
@@ -191,7 +384,7 @@ impl Ppu {
             let window_tilemap = if lcdc & 0b01000000 != 0 { 0x1C00 } else { 0x1800 };
             let wx = self.io_regs[0x004B];
             let wy = self.io_regs[0x004A];
-            if lcdc & 0b00100000 != 0 && ly >= wy && wx >= 7 && wx < 167 {
+            if lcdc & 0b00100000 != 0 && ly >= wy && (7..167).contains(&wx) {
                 for x_counter in 0..20 {
                     let addr = (window_tilemap +
                                     (x_counter as u16) +
@@ -208,8 +401,9 @@ impl Ppu {
                     for px in 0..8 {
                         let linepos = x_counter as u16 * 8 + (px + wx - 7) as u16;
                         if linepos < 160 {
-                            let px_val = if b1 & (1 << 7 - px) != 0 { 1 } else { 0 } | if b2 & (1 << 7 - px) != 0 { 2 } else { 0 };
+                            let px_val = if b1 & (1 << (7 - px)) != 0 { 1 } else { 0 } | if b2 & (1 << (7 - px)) != 0 { 2 } else { 0 };
                             let color = (bg_palette >> (px_val * 2)) & 0x3;
+                            bg_line[linepos as usize] = px_val;
                             line[linepos as usize] = color;
                         }
                     }
@@ -223,7 +417,8 @@ impl Ppu {
         // Sprites: iterate the OAM and draw pixels on the line that we need
         // But only if LCDC bit 1 is set: enable/disable sprites 
         if lcdc & 0b00000010 != 0 {
-            let mut sprite_line: [u8; 160] = [0; 160];
+            // The color of the object pixel that wins at each position, or None if the background is drawn over it
+            let mut sprite_line: [Option<u8>; 160] = [None; 160];
             let mut priority: [u8; 160] = [0xFF; 160];
 
             // Sprite height based on LCDC bit 2: if set "tall-sprite" mode
@@ -253,17 +448,16 @@ impl Ppu {
                     for px in 0..8 {
                         if x + px >= 8 {
                             let linepos = (x + px - 8) as usize;
-                            if linepos > 0 && linepos < 160 {
+                            if linepos < 160 {
                                 let sprite_pos = if xflip { px } else { 7 - px };
                                 let px_val: u8 = if b1 & (1 << sprite_pos) != 0 { 1 } else { 0 } 
                                                     | if b2 & (1 << sprite_pos) != 0 { 2 } else { 0 };
                                 let color = (sprite_palette >> (px_val * 2)) & 0x3;
 
-                                if priority[linepos] > x {
+                                // Color 0 is transparent, and lets objects with lower priority show through
+                                if px_val != 0 && priority[linepos] > x {
                                     priority[linepos] = x;
-
-                                    if color == 0 { sprite_line[linepos] = line[linepos]; }
-                                    else if line[linepos] == 0 || !background_priority { sprite_line[linepos] = color; }
+                                    sprite_line[linepos] = if bg_line[linepos] == 0 || !background_priority { Some(color) } else { None };
                                 }
                             }
                         }
@@ -275,14 +469,333 @@ impl Ppu {
             }
 
             for linepos in 0..160 {
-                if sprite_line[linepos] != 0 {
-                    line[linepos] = sprite_line[linepos];
+                if let Some(color) = sprite_line[linepos] {
+                    line[linepos] = color;
                 }
             }
         }
 
 
 
-        lcd.set_line(ly, line);
+        if !self.skip_frame { lcd.set_line(ly, line); }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Ppu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.oam);
+        w.write_bytes(&self.io_regs);
+
+        w.write_u8(match self.mode {
+            PpuMode::HBlank => 0,
+            PpuMode::VBlank => 1,
+            PpuMode::OAMScan => 2,
+            PpuMode::Drawing => 3,
+        });
+        w.write_u32(self.line_cycles);
+        w.write_u32(self.mode_3_end);
+        w.write_bool(self.reached_window);
+        w.write_u16(self.window_line_counter);
+        w.write_bool(self.frame_complete);
+        w.write_bool(self.first_line);
+        w.write_bool(self.skip_frame);
+        w.write_bool(self.fifo_line);
+        self.fifo.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        r.read_bytes(&mut self.vram)?;
+        r.read_bytes(&mut self.oam)?;
+        r.read_bytes(&mut self.io_regs)?;
+
+        self.mode = match r.read_u8()? {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OAMScan,
+            3 => PpuMode::Drawing,
+            _ => return Err(SavestateError::InvalidFormat),
+        };
+        self.line_cycles = r.read_u32()?;
+        self.mode_3_end = r.read_u32()?;
+        self.reached_window = r.read_bool()?;
+        self.window_line_counter = r.read_u16()?;
+        self.frame_complete = r.read_bool()?;
+        self.first_line = r.read_bool()?;
+        self.skip_frame = r.read_bool()?;
+        self.fifo_line = r.read_bool()?;
+        // LY has to agree with the mode (and the first line after turning the LCD on is line 0),
+        // and both counters have to stay within a line (or a frame, for the window)
+        let ly = self.io_regs[0x0044];
+        if ly > 153 || (ly >= 144) != (self.mode == PpuMode::VBlank) || (self.first_line && ly != 0)
+            || self.line_cycles >= 456 || !(252..456).contains(&self.mode_3_end) || self.window_line_counter > LCD_HEIGHT as u16 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.fifo.load_state(r)?;
+        // A line the FIFO renderer is drawing can't be finished already
+        if self.fifo_line && self.mode == PpuMode::Drawing && self.fifo.line_done() {
+            return Err(SavestateError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lcd::{LCD_HEIGHT, LCD_WIDTH};
+
+    // A PPU with a background, window and objects set up, drawing with the given render mode
+    fn setup(mode: RenderMode) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_render_mode(mode);
+        // VRAM and OAM are only freely accessible with the LCD off
+        ppu.write(0xFF40, 0);
+        for addr in 0x8000..0x9800u16 {
+            ppu.write(addr, (addr as u8).wrapping_mul(37) ^ (addr >> 4) as u8);
+        }
+        for addr in 0x9800..0xA000u16 {
+            ppu.write(addr, (addr % 61) as u8);
+        }
+        for (i, addr) in (0xFE00..0xFEA0u16).step_by(4).enumerate() {
+            let i = i as u8;
+            ppu.write(addr, 16 + (i as u16 * 7 % 144) as u8);
+            ppu.write(addr + 1, 8 + (i as u16 * 13 % 160) as u8);
+            ppu.write(addr + 2, i * 3);
+            ppu.write(addr + 3, (i % 8) << 5 | (i % 2) << 4);
+        }
+        // Background, window (from line 40, column 60) and objects on, with 8x8 objects and 0x8000 tile data
+        ppu.write(0xFF40, 0b11110011);
+        ppu.write(0xFF42, 13);
+        ppu.write(0xFF43, 21);
+        // Palettes that map some color indices other than 0 to shade 0, as transparency and priority go by index
+        ppu.write(0xFF47, 0b00100111);
+        ppu.write(0xFF48, 0b11100100);
+        ppu.write(0xFF49, 0b00111001);
+        ppu.write(0xFF4A, 40);
+        ppu.write(0xFF4B, 67);
+        ppu
+    }
+
+    fn run_until_mode(ppu: &mut Ppu, mode: u8) {
+        let mut lcd = Lcd::new();
+        let mut interrupts = Interrupts::new();
+        while ppu.read(0xFF41) & 0x03 != mode { ppu.tick(&mut lcd, &mut interrupts, 1); }
+    }
+
+    fn run_frames(ppu: &mut Ppu, frames: u32) -> Lcd {
+        let mut lcd = Lcd::new();
+        let mut interrupts = Interrupts::new();
+        for _ in 0..frames * 17556 {
+            ppu.tick(&mut lcd, &mut interrupts, 1);
+        }
+        lcd
+    }
+
+    fn mode_3_length(ppu: &mut Ppu) -> u32 {
+        // Runs until the next line's mode 3, and counts the m-cycles it takes
+        let mut lcd = Lcd::new();
+        let mut interrupts = Interrupts::new();
+        while ppu.read(0xFF41) & 0x03 == 3 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        while ppu.read(0xFF41) & 0x03 != 3 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        let mut length = 0;
+        while ppu.read(0xFF41) & 0x03 == 3 {
+            ppu.tick(&mut lcd, &mut interrupts, 1);
+            length += 1;
+        }
+        length
+    }
+
+    #[test]
+    fn fifo_renders_a_static_frame_like_the_scanline_renderer() {
+        let scanline = run_frames(&mut setup(RenderMode::Scanline), 2);
+        let fifo = run_frames(&mut setup(RenderMode::Fifo), 2);
+        for y in 0..LCD_HEIGHT {
+            let row = y * LCD_WIDTH;
+            assert_eq!(scanline.pixels[row..row + LCD_WIDTH], fifo.pixels[row..row + LCD_WIDTH], "line {}", y);
+        }
+    }
+
+    fn show_window_on_next_line(ppu: &mut Ppu) {
+        // Window on from the next line on, starting at column 80
+        let ly = ppu.read(0xFF44);
+        ppu.write(0xFF4A, ly + 1);
+        ppu.write(0xFF4B, 87);
+        ppu.write(0xFF40, ppu.read(0xFF40) | 0b00100000);
+    }
+
+    fn put_objects_on_next_line(ppu: &mut Ppu, count: u8) {
+        // Objects on, with the given number of 8x8 objects at x=42 on the next line
+        let ly = ppu.read(0xFF44);
+        for (i, addr) in (0xFE00..0xFE28u16).step_by(4).enumerate() {
+            ppu.write(addr, if (i as u8) < count { ly + 17 } else { 0 });
+            ppu.write(addr + 1, 50);
+        }
+        ppu.write(0xFF40, ppu.read(0xFF40) | 0b00000010);
+    }
+
+    fn scanline_estimate(ppu: &mut Ppu) -> u32 {
+        // Runs through the next line's mode 3, and returns how many dots the scanline renderer gave it
+        let mut lcd = Lcd::new();
+        let mut interrupts = Interrupts::new();
+        while ppu.read(0xFF41) & 0x03 != 3 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        let dots = ppu.mode_3_end - 80;
+        while ppu.read(0xFF41) & 0x03 == 3 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        dots
+    }
+
+    #[test]
+    fn mode_3_length_depends_on_scroll_window_and_objects() {
+        let mut ppu = Ppu::new();
+        ppu.set_render_mode(RenderMode::Fifo);
+        ppu.write(0xFF40, 0b10010001);
+        assert_eq!(mode_3_length(&mut ppu), 43);
+
+        // Fine scrolling discards pixels at the start of the line
+        ppu.write(0xFF43, 4);
+        assert_eq!(mode_3_length(&mut ppu), 44);
+        ppu.write(0xFF43, 0);
+
+        // The fetcher starts over at the window
+        show_window_on_next_line(&mut ppu);
+        assert_eq!(mode_3_length(&mut ppu), 45);
+        ppu.write(0xFF40, 0b10010001);
+
+        // Every object on the line stalls the renderer while it's fetched
+        put_objects_on_next_line(&mut ppu, 1);
+        assert_eq!(mode_3_length(&mut ppu), 46);
+        put_objects_on_next_line(&mut ppu, 10);
+        assert_eq!(mode_3_length(&mut ppu), 59);
+    }
+
+    #[test]
+    fn scanline_mode_3_length_is_estimated() {
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF40, 0b10010001);
+        assert_eq!(scanline_estimate(&mut ppu), 172);
+
+        ppu.write(0xFF43, 4);
+        assert_eq!(scanline_estimate(&mut ppu), 176);
+        ppu.write(0xFF43, 0);
+
+        show_window_on_next_line(&mut ppu);
+        assert_eq!(scanline_estimate(&mut ppu), 178);
+        ppu.write(0xFF40, 0b10010001);
+
+        // Objects take 6 dots each, and the first one over a background tile stalls for its alignment:
+        // x=42 is 2 pixels into its tile, so 3 more
+        put_objects_on_next_line(&mut ppu, 1);
+        assert_eq!(scanline_estimate(&mut ppu), 172 + 6 + 3);
+        put_objects_on_next_line(&mut ppu, 10);
+        assert_eq!(scanline_estimate(&mut ppu), 172 + 10 * 6 + 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn strict_access_blocks_vram_and_oam_while_in_use() {
+        let mut ppu = Ppu::new();
+        ppu.set_render_mode(RenderMode::Fifo);
+        ppu.write(0xFF40, 0b10010001);
+
+        run_until_mode(&mut ppu, 2);
+        ppu.write(0x8000, 0x12);
+        ppu.write(0xFE00, 0x34);
+        assert_eq!(ppu.read(0x8000), 0x12);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+
+        run_until_mode(&mut ppu, 3);
+        ppu.write(0x8000, 0x56);
+        assert_eq!(ppu.read(0x8000), 0xFF);
+        assert_eq!(ppu.read(0xFE00), 0xFF);
+
+        run_until_mode(&mut ppu, 0);
+        assert_eq!(ppu.read(0x8000), 0x12);
+        assert_eq!(ppu.read(0xFE00), 0x00);
+
+        // Lenient access ignores the PPU mode
+        ppu.set_access_mode(AccessMode::Lenient);
+        run_until_mode(&mut ppu, 3);
+        ppu.write(0x8000, 0x56);
+        assert_eq!(ppu.read(0x8000), 0x56);
+    }
+
+    #[test]
+    fn lcd_off_stops_the_ppu_and_on_restarts_it() {
+        let mut ppu = Ppu::new();
+        let mut lcd = Lcd::new();
+        let mut interrupts = Interrupts::new();
+        for _ in 0..1000 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+
+        // Turning the LCD off resets LY and stops everything, interrupts included
+        ppu.write(0xFF40, 0x11);
+        interrupts.write(0xFF0F, 0);
+        for _ in 0..20000 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        assert_eq!(ppu.read(0xFF44), 0);
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0);
+        assert_eq!(interrupts.read(0xFF0F), 0xE0);
+        assert!(!ppu.take_frame_complete());
+
+        // The first line after turning it on skips OAM scan, and is 4 dots short
+        ppu.write(0xFF47, 0xFF);
+        ppu.write(0xFF40, 0x91);
+        for _ in 0..18 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        assert_eq!(ppu.read(0xFF41) & 0x03, 0);
+        ppu.tick(&mut lcd, &mut interrupts, 1);
+        assert_eq!(ppu.read(0xFF41) & 0x03, 3);
+        for _ in 19..112 { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        assert_eq!(ppu.read(0xFF44), 0);
+        ppu.tick(&mut lcd, &mut interrupts, 1);
+        assert_eq!(ppu.read(0xFF44), 1);
+
+        // The first frame isn't shown, the next one is
+        while !ppu.take_frame_complete() { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        assert!(lcd.pixels.iter().all(|&p| p == 0));
+        while !ppu.take_frame_complete() { ppu.tick(&mut lcd, &mut interrupts, 1); }
+        assert!(lcd.pixels.iter().all(|&p| p == 3));
+    }
+
+    #[test]
+    fn switching_renderers_mid_line_finishes_the_line() {
+        // The first PPU switches to the scanline renderer in the middle of mode 3, the second doesn't
+        let mut ppus = [setup(RenderMode::Fifo), setup(RenderMode::Fifo)];
+        let mut lcds = [Lcd::new(), Lcd::new()];
+        let mut interrupts = Interrupts::new();
+
+        for (ppu, lcd) in ppus.iter_mut().zip(lcds.iter_mut()) {
+            // The first frame after turning the LCD on isn't shown
+            while !ppu.take_frame_complete() { ppu.tick(lcd, &mut interrupts, 1); }
+            while ppu.read(0xFF41) & 0x03 != 3 { ppu.tick(lcd, &mut interrupts, 1); }
+            ppu.tick(lcd, &mut interrupts, 1);
+        }
+        ppus[0].set_render_mode(RenderMode::Scanline);
+        for (ppu, lcd) in ppus.iter_mut().zip(lcds.iter_mut()) {
+            while ppu.read(0xFF41) & 0x03 != 0 { ppu.tick(lcd, &mut interrupts, 1); }
+        }
+
+        let line = &lcds[0].pixels[..LCD_WIDTH];
+        assert!(line.iter().any(|&p| p != 0));
+        assert_eq!(line, &lcds[1].pixels[..LCD_WIDTH]);
+    }
+
+    #[test]
+    fn corrupt_state_is_rejected() {
+        let mut w = StateWriter::new();
+        Ppu::new().save_state(&mut w);
+        let data = w.into_bytes();
+        assert!(Ppu::new().load_state(&mut StateReader::new(&data)).is_ok());
+
+        // LY past the last line, and a line that never ends, after VRAM, OAM and the IO registers (and the mode for the latter)
+        let ly = 8192 + 160 + 0x44;
+        let line_cycles = 8192 + 160 + 512 + 1;
+        for (offset, bytes) in [(ly, &[200][..]), (line_cycles, &456u32.to_le_bytes()[..])] {
+            let mut data = data.clone();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            assert!(matches!(Ppu::new().load_state(&mut StateReader::new(&data)), Err(SavestateError::InvalidFormat)));
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::{Cartridge, CartridgeType};
+
+// Persists battery-backed cartridge RAM to a .sav file next to the ROM.
+pub struct BatterySave {
+    path: PathBuf,
+    // Cartridge::save_data_key as of the last read/write, so we only touch the disk when the save changes
+    last_saved: Vec<u8>,
+}
+
+impl BatterySave {
+    pub fn new(path: &Path) -> Self {
+        BatterySave { path: path.to_path_buf(), last_saved: Vec::new() }
+    }
+
+    pub fn for_rom(rom_path: &Path) -> Self {
+        Self::new(&rom_path.with_extension("sav"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&mut self, cart: &mut CartridgeType) -> io::Result<()> {
+        // Load an existing save file into the cartridge. A missing file is not an error (new game).
+        if !cart.has_battery() {
+            return Ok(());
+        }
+
+        match fs::read(&self.path) {
+            Ok(data) => {
+                cart.load_save_data(&data);
+                self.last_saved = cart.save_data_key();
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.last_saved = cart.save_data_key();
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn flush(&mut self, cart: &CartridgeType) -> io::Result<bool> {
+        // Write cartridge RAM to disk if it has changed since the last flush.
+        // Returns whether anything was written.
+        if !cart.has_battery() {
+            return Ok(false);
+        }
+
+        let key = cart.save_data_key();
+        if key == self.last_saved {
+            return Ok(false);
+        }
+        let data = cart.save_data();
+
+        // Write to a temporary file first and then rename it over the old save,
+        // so a crash part-way through a write can never leave a corrupted save behind
+        let tmp_path = self.path.with_extension("sav.tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.last_saved = key;
+        Ok(true)
+    }
+}
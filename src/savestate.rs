@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Save states are a small header followed by each component's state, in a fixed order.
+// Bump the version whenever the layout of any component's state changes.
+pub const SAVESTATE_VERSION: u16 = 1;
+const MAGIC: &[u8; 4] = b"RGBL";
+
+#[derive(Debug)]
+pub enum SavestateError {
+    Io(io::Error),
+    // Not a save state at all, or a corrupted one
+    InvalidFormat,
+    UnsupportedVersion { found: u16, expected: u16 },
+    // The save state was made with a different ROM
+    RomMismatch { state_title: String, rom_title: String },
+}
+
+impl fmt::Display for SavestateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SavestateError::Io(e) => write!(f, "could not access save state: {}", e),
+            SavestateError::InvalidFormat => write!(f, "not a valid save state"),
+            SavestateError::UnsupportedVersion { found, expected } =>
+                write!(f, "save state version {} is not supported (expected version {})", found, expected),
+            SavestateError::RomMismatch { state_title, rom_title } =>
+                write!(f, "save state was made for \"{}\", but \"{}\" is loaded", state_title, rom_title),
+        }
+    }
+}
+
+impl Error for SavestateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SavestateError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SavestateError {
+    fn from(e: io::Error) -> Self {
+        SavestateError::Io(e)
+    }
+}
+
+// Implemented by every component that is part of the machine state
+pub trait Savestate {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError>;
+}
+
+// Identifies the ROM a save state belongs to
+pub struct RomId {
+    pub title: String,
+    pub hash: u64,
+}
+
+impl RomId {
+    pub fn new(rom: &[u8]) -> Self {
+        let title = rom.get(0x0134..0x0144).unwrap_or(&[])
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect();
+
+        // 64 bit FNV-1a hash of the whole ROM
+        let mut hash: u64 = 0xCBF29CE484222325;
+        for &b in rom {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x100000001B3);
+        }
+
+        RomId { title, hash }
+    }
+}
+
+pub fn write_header(w: &mut StateWriter, rom: &RomId) {
+    w.write_bytes(MAGIC);
+    w.write_u16(SAVESTATE_VERSION);
+    w.write_u64(rom.hash);
+    w.write_slice(rom.title.as_bytes());
+}
+
+pub fn read_header(r: &mut StateReader, rom: &RomId) -> Result<(), SavestateError> {
+    let mut magic = [0; 4];
+    r.read_bytes(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SavestateError::InvalidFormat);
+    }
+
+    let version = r.read_u16()?;
+    if version != SAVESTATE_VERSION {
+        return Err(SavestateError::UnsupportedVersion { found: version, expected: SAVESTATE_VERSION });
+    }
+
+    let hash = r.read_u64()?;
+    let title = String::from_utf8_lossy(&r.read_slice()?).into_owned();
+    if hash != rom.hash {
+        return Err(SavestateError::RomMismatch { state_title: title, rom_title: rom.title.clone() });
+    }
+    Ok(())
+}
+
+
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, v: u8) { self.data.push(v); }
+    pub fn write_bool(&mut self, v: bool) { self.data.push(v as u8); }
+    pub fn write_u16(&mut self, v: u16) { self.data.extend_from_slice(&v.to_le_bytes()); }
+    pub fn write_u32(&mut self, v: u32) { self.data.extend_from_slice(&v.to_le_bytes()); }
+    pub fn write_u64(&mut self, v: u64) { self.data.extend_from_slice(&v.to_le_bytes()); }
+    pub fn write_i32(&mut self, v: i32) { self.data.extend_from_slice(&v.to_le_bytes()); }
+    pub fn write_f32(&mut self, v: f32) { self.data.extend_from_slice(&v.to_le_bytes()); }
+
+    // Fixed-size data, whose length is known when reading it back
+    pub fn write_bytes(&mut self, v: &[u8]) { self.data.extend_from_slice(v); }
+
+    // Variable-size data, written with its length
+    pub fn write_slice(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.data.extend_from_slice(v);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SavestateError> {
+        // Running out of data means the save state was truncated
+        let end = self.pos.checked_add(len).ok_or(SavestateError::InvalidFormat)?;
+        let bytes = self.data.get(self.pos..end).ok_or(SavestateError::InvalidFormat)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], SavestateError> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SavestateError> { Ok(self.take(1)?[0]) }
+    pub fn read_bool(&mut self) -> Result<bool, SavestateError> { Ok(self.read_u8()? != 0) }
+    pub fn read_u16(&mut self) -> Result<u16, SavestateError> { Ok(u16::from_le_bytes(self.take_array()?)) }
+    pub fn read_u32(&mut self) -> Result<u32, SavestateError> { Ok(u32::from_le_bytes(self.take_array()?)) }
+    pub fn read_u64(&mut self) -> Result<u64, SavestateError> { Ok(u64::from_le_bytes(self.take_array()?)) }
+    pub fn read_i32(&mut self) -> Result<i32, SavestateError> { Ok(i32::from_le_bytes(self.take_array()?)) }
+    pub fn read_f32(&mut self) -> Result<f32, SavestateError> { Ok(f32::from_le_bytes(self.take_array()?)) }
+
+    pub fn read_bytes(&mut self, v: &mut [u8]) -> Result<(), SavestateError> {
+        v.copy_from_slice(self.take(v.len())?);
+        Ok(())
+    }
+
+    pub fn read_slice(&mut self) -> Result<Vec<u8>, SavestateError> {
+        let len = self.read_u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub fn read_slice_into(&mut self, v: &mut [u8]) -> Result<(), SavestateError> {
+        // Reads variable-size data that must match the size of v (e.g. cartridge RAM)
+        let len = self.read_u32()? as usize;
+        if len != v.len() {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.read_bytes(v)
+    }
+
+    pub fn finish(&self) -> Result<(), SavestateError> {
+        // Leftover data means the layout didn't match what we expected
+        if self.pos == self.data.len() { Ok(()) } else { Err(SavestateError::InvalidFormat) }
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
+
+// With the internal clock, the serial port shifts one bit every 512 t-cycles (8192 Hz)
+const MCYCLES_PER_BIT: u32 = 128;
+
+// Something plugged into the link cable port.
+pub trait SerialDevice: Send {
+    // Called when a transfer starts: receives the byte the Gameboy is sending, and returns the byte it receives
+    fn exchange(&mut self, byte: u8) -> u8;
+}
+
+// No link cable connected: the input line is pulled high, so every bit received is a 1
+pub struct NoCable;
+
+impl SerialDevice for NoCable {
+    fn exchange(&mut self, _byte: u8) -> u8 { 0xFF }
+}
+
+// A cable connected back into the same port: every byte sent is received again
+pub struct Loopback;
+
+impl SerialDevice for Loopback {
+    fn exchange(&mut self, byte: u8) -> u8 { byte }
+}
+
+// Prints every byte sent as a character on stdout (test ROMs report their results this way)
+pub struct StdoutSerial;
+
+impl SerialDevice for StdoutSerial {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        print!("{}", byte as char);
+        io::stdout().flush().ok();
+        0xFF
+    }
+}
+
+// Records every byte sent, so the output can be inspected from outside the emulator.
+// Clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct CaptureSerial {
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CaptureSerial {
+    pub fn new() -> Self {
+        CaptureSerial { output: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    pub fn output(&self) -> Vec<u8> {
+        self.output.lock().unwrap().clone()
+    }
+}
+
+impl SerialDevice for CaptureSerial {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.output.lock().unwrap().push(byte);
+        0xFF
+    }
+}
+
+pub struct Serial {
+    // SB: data being shifted out/in, SC: transfer control
+    sb: u8,
+    sc: u8,
+    device: Box<dyn SerialDevice>,
+
+    // Byte being received from the device, and how many bits of the transfer are left
+    incoming: u8,
+    bits_remaining: u8,
+    cycles: u32,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial { sb: 0, sc: 0, device: Box::new(NoCable), incoming: 0xFF, bits_remaining: 0, cycles: 0 }
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    pub fn take_device(&mut self) -> Box<dyn SerialDevice> {
+        // Unplug the device, leaving the port without a cable
+        mem::replace(&mut self.device, Box::new(NoCable))
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            // Bits 1-6 of SC are unused and read as 1
+            0xFF02 => self.sc | 0x7E,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.sb = value,
+            0xFF02 => {
+                self.sc = value & 0x81;
+                // A transfer only runs on its own with the internal clock (bit 0).
+                // With an external clock the other side would drive it, and nothing does.
+                if self.sc == 0x81 {
+                    self.incoming = self.device.exchange(self.sb);
+                    self.bits_remaining = 8;
+                    self.cycles = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn tick(&mut self, m_cycles: u8) -> bool {
+        // Returns true when a transfer finishes, which requests the serial interrupt
+        if self.bits_remaining == 0 {
+            return false;
+        }
+
+        self.cycles += m_cycles as u32;
+        while self.cycles >= MCYCLES_PER_BIT && self.bits_remaining > 0 {
+            self.cycles -= MCYCLES_PER_BIT;
+
+            // Shift out the top bit of SB while shifting in the next bit of the received byte
+            self.bits_remaining -= 1;
+            self.sb = (self.sb << 1) | ((self.incoming >> self.bits_remaining) & 0x01);
+        }
+
+        if self.bits_remaining == 0 {
+            // Transfer complete: clear the start bit
+            self.sc &= 0x7F;
+            return true;
+        }
+        false
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A copy of the port has no cable plugged in: the device stays connected to the original
+impl Clone for Serial {
+    fn clone(&self) -> Self {
+        Serial { device: Box::new(NoCable), ..*self }
+    }
+}
+
+impl Savestate for Serial {
+    // The connected device isn't part of the machine state and stays as it is
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sb);
+        w.write_u8(self.sc);
+        w.write_u8(self.incoming);
+        w.write_u8(self.bits_remaining);
+        w.write_u32(self.cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.sb = r.read_u8()?;
+        self.sc = r.read_u8()?;
+        self.incoming = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        if self.bits_remaining > 8 {
+            return Err(SavestateError::InvalidFormat);
+        }
+        self.cycles = r.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrupt_state_is_rejected() {
+        let mut w = StateWriter::new();
+        Serial::new().save_state(&mut w);
+        let mut data = w.into_bytes();
+
+        // More bits left to shift than a byte has
+        data[3] = 9;
+        assert!(matches!(Serial::new().load_state(&mut StateReader::new(&data)), Err(SavestateError::InvalidFormat)));
+    }
+}
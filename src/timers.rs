@@ -1,57 +1,210 @@
-use crate::mmu::Mmu;
+use crate::savestate::{Savestate, SavestateError, StateReader, StateWriter};
 
+// State of TIMA after it overflows: it reads 0 for one m-cycle, then TMA is loaded into it
+// (and the interrupt requested) during the next one
+#[derive(Clone, Copy, PartialEq)]
+enum Reload {
+    None,
+    Pending,
+    Reloading,
+}
+
+#[derive(Clone)]
 pub struct Timers {
-    div_partial: u8,
-    tima_partial: u16,
+    // The 16-bit system counter, incremented every t-cycle. DIV is its upper byte.
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: Reload,
 }
 
 impl Timers {
     pub fn new() -> Timers {
-        Timers { div_partial: 0, tima_partial: 0 }
+        // Counter value left behind by the DMG boot ROM
+        Timers { counter: 0xABCC, tima: 0, tma: 0, tac: 0, reload: Reload::None }
     }
 
-    pub fn tick(&mut self, mmu: &mut Mmu, mcycles: u8) {
-        // Given an amount of m-cycles, do timer-related tasks and set interrupts
-        let t_cycles = mcycles * 4;
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.counter >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
 
-        // Do DIV register
-        let (res, inc_div) = self.div_partial.overflowing_add(t_cycles);
-        self.div_partial = res;
-        if inc_div {
-            let prev_div = mmu.read(0xFF04);
-            mmu.write(0xFF04, prev_div.wrapping_add(1));
+    pub fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF04 => self.reset_div(),
+            0xFF05 => match self.reload {
+                // Writing TIMA in the cycle after an overflow cancels the reload (and the interrupt)
+                Reload::Pending => { self.tima = value; self.reload = Reload::None; }
+                // While TMA is being loaded, TIMA writes are ignored
+                Reload::Reloading => (),
+                Reload::None => self.tima = value,
+            },
+            0xFF06 => {
+                // A TMA write during the reload goes straight through to TIMA as well
+                self.tma = value;
+                if self.reload == Reload::Reloading { self.tima = value; }
+            }
+            0xFF07 => {
+                // Changing TAC can make the timer input fall, which increments TIMA
+                let before = self.input();
+                self.tac = value & 0x07;
+                if before && !self.input() { self.increment_tima(); }
+            }
+            _ => (),
         }
+    }
 
-        // Do TIMA register
-        let tac = mmu.read(0xFF07);
-        // Check if the timer is enabled
-        if tac & 0b100 != 0 {
-            // Do partial timer ticks according to cpu progress
-            self.tima_partial += t_cycles as u16;
-            let timer_step = match tac & 0b011 {
-                0b00 => 1024,
-                0b01 => 16,
-                0b10 => 64,
-                0b11 => 256,
-                _ => panic!("invalid TAC speed")
-            };
+    pub fn reset_div(&mut self) {
+        // Any write to DIV clears the whole counter, which can make the timer input fall too
+        let before = self.input();
+        self.counter = 0;
+        if before { self.increment_tima(); }
+    }
 
-            // Check partial tick progress compared to threshold
-            while self.tima_partial > timer_step {
-                // Increment TIMA register, throw interrupt if wrapping
-                let prev_tima = mmu.read(0xFF05);
-                let (new_tima, overflow) = prev_tima.overflowing_add(1);
-                // If TIMA overflowed, reset it to TMA and throw interrupt
-                if overflow {
-                    let tma = mmu.read(0xFF06);
-                    mmu.write(0xFF05, tma);
-                    mmu.write(0xFF0F, mmu.read(0xFF0F) | 0b00100);
-                } else {
-                    mmu.write(0xFF05, new_tima);
+    pub fn tick(&mut self, mcycles: u8) -> bool {
+        // Given an amount of m-cycles, advance the timers. Returns true if the timer interrupt should be requested.
+        let mut interrupt = false;
+        for _ in 0..mcycles {
+            self.reload = match self.reload {
+                Reload::Pending => {
+                    self.tima = self.tma;
+                    interrupt = true;
+                    Reload::Reloading
                 }
+                _ => Reload::None,
+            };
 
-                self.tima_partial -= timer_step;
-            }
+            let before = self.input();
+            self.counter = self.counter.wrapping_add(4);
+            if before && !self.input() { self.increment_tima(); }
         }
+        interrupt
+    }
+
+    fn input(&self) -> bool {
+        // TIMA is clocked by the falling edge of one counter bit (selected by TAC), ANDed with the timer enable bit
+        let bit = match self.tac & 0b011 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & 0b100 != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow { self.reload = Reload::Pending; }
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Timers {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_bytes(&[self.tima, self.tma, self.tac]);
+        w.write_u8(match self.reload {
+            Reload::None => 0,
+            Reload::Pending => 1,
+            Reload::Reloading => 2,
+        });
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SavestateError> {
+        self.counter = r.read_u16()?;
+        let mut regs = [0; 3];
+        r.read_bytes(&mut regs)?;
+        let [tima, tma, tac] = regs;
+        self.tima = tima;
+        self.tma = tma;
+        self.tac = tac;
+        self.reload = match r.read_u8()? {
+            0 => Reload::None,
+            1 => Reload::Pending,
+            2 => Reload::Reloading,
+            _ => return Err(SavestateError::InvalidFormat),
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timers with a cleared counter, clocked from bit 3 (TIMA increments every 4 m-cycles)
+    fn setup() -> Timers {
+        let mut timers = Timers::new();
+        timers.write(0xFF04, 0);
+        timers.write(0xFF07, 0b101);
+        timers
+    }
+
+    #[test]
+    fn tima_increments_on_the_falling_edge() {
+        let mut timers = setup();
+        timers.tick(3);
+        assert_eq!(timers.read(0xFF05), 0);
+        timers.tick(1);
+        assert_eq!(timers.read(0xFF05), 1);
+        timers.tick(4);
+        assert_eq!(timers.read(0xFF05), 2);
+    }
+
+    #[test]
+    fn div_write_and_tac_change_can_increment_tima() {
+        let mut timers = setup();
+        // Bit 3 of the counter is now set, so clearing the counter makes the input fall
+        timers.tick(2);
+        timers.write(0xFF04, 0x12);
+        assert_eq!(timers.read(0xFF04), 0);
+        assert_eq!(timers.read(0xFF05), 1);
+
+        // Same for switching to a clock bit that isn't set
+        timers.tick(2);
+        timers.write(0xFF07, 0b100);
+        assert_eq!(timers.read(0xFF05), 2);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_cycle_later() {
+        let mut timers = setup();
+        timers.write(0xFF05, 0xFF);
+        timers.write(0xFF06, 0x42);
+
+        assert!(!timers.tick(4));
+        assert_eq!(timers.read(0xFF05), 0);
+        assert!(timers.tick(1));
+        assert_eq!(timers.read(0xFF05), 0x42);
+
+        // TIMA writes during the reload are ignored, TMA writes go through
+        timers.write(0xFF05, 0x10);
+        assert_eq!(timers.read(0xFF05), 0x42);
+        timers.write(0xFF06, 0x20);
+        assert_eq!(timers.read(0xFF05), 0x20);
+    }
+
+    #[test]
+    fn tima_write_after_overflow_cancels_the_reload() {
+        let mut timers = setup();
+        timers.write(0xFF05, 0xFF);
+        timers.write(0xFF06, 0x42);
+
+        timers.tick(4);
+        timers.write(0xFF05, 0x10);
+        assert!(!timers.tick(1));
+        assert_eq!(timers.read(0xFF05), 0x10);
     }
 }
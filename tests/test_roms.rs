@@ -0,0 +1,239 @@
+// Runs the Blargg and Mooneye test ROM suites headlessly.
+//
+// ROMs are looked up in ./carts (or the directory in RGBL_TEST_ROMS): every .gb file below a directory whose
+// name starts with "blargg" is judged as a Blargg test, and below one starting with "mooneye" as a Mooneye test.
+// ROMs that are known not to pass yet can be listed (by path relative to that directory, one per line)
+// in known_failures.txt there, so that only regressions fail the test.
+// If the directory doesn't exist the test is skipped, as the ROMs aren't distributed with the emulator.
+//
+// Run with `cargo test --release --test test_roms -- --nocapture` to see the results table.
+
+use std::collections::HashSet;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use rgbl::serial::CaptureSerial;
+use rgbl::Motherboard;
+
+// Some Blargg tests (e.g. the combined cpu_instrs) take close to a minute of emulated time
+const BLARGG_TIMEOUT_FRAMES: u32 = 4000;
+const MOONEYE_TIMEOUT_FRAMES: u32 = 1200;
+
+// Mooneye tests execute LD B,B when they're done, with this signature in the registers if they passed
+const LD_B_B: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Suite {
+    Blargg,
+    Mooneye,
+}
+
+enum Outcome {
+    Passed,
+    Failed(String),
+    Error(String),
+    Panicked(String),
+    Timeout,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Passed => "pass",
+            Outcome::Failed(_) => "FAIL",
+            Outcome::Error(_) => "ERROR",
+            Outcome::Panicked(_) => "PANIC",
+            Outcome::Timeout => "TIMEOUT",
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            Outcome::Failed(detail) | Outcome::Error(detail) | Outcome::Panicked(detail) => detail,
+            _ => "",
+        }
+    }
+}
+
+struct TestRom {
+    // Path relative to the test ROM directory
+    name: String,
+    path: PathBuf,
+    suite: Suite,
+}
+
+#[test]
+fn test_roms() {
+    let dir = PathBuf::from(std::env::var("RGBL_TEST_ROMS").unwrap_or_else(|_| "carts".to_string()));
+    if !dir.is_dir() {
+        println!("skipping test ROMs: {} not found", dir.display());
+        return;
+    }
+
+    let mut roms = Vec::new();
+    find_roms(&dir, &dir, None, &mut roms);
+    roms.sort_by(|a, b| a.name.cmp(&b.name));
+    if roms.is_empty() {
+        println!("skipping test ROMs: no Blargg or Mooneye ROMs in {}", dir.display());
+        return;
+    }
+
+    let known_failures: HashSet<String> = fs::read_to_string(dir.join("known_failures.txt"))
+        .unwrap_or_default()
+        .lines()
+        .map(|l| l.trim().replace('\\', "/"))
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+
+    let outcomes = run_all(&roms);
+
+    // Print the results table, and collect everything that didn't go as expected
+    let width = roms.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let mut regressions = Vec::new();
+    let mut passed = 0;
+    for (rom, outcome) in roms.iter().zip(&outcomes) {
+        let known = known_failures.contains(&rom.name);
+        let note = match (outcome, known) {
+            (Outcome::Passed, true) => "(listed as a known failure)",
+            (Outcome::Passed, false) => "",
+            (_, true) => "(known failure)",
+            (_, false) => { regressions.push(rom.name.as_str()); "" }
+        };
+        if let Outcome::Passed = outcome { passed += 1; }
+        let row = format!("{:<width$}  {:<7}  {} {}", rom.name, outcome.label(), note, outcome.detail(), width = width);
+        println!("{}", row.trim_end());
+    }
+    println!("{}/{} test ROMs passed", passed, roms.len());
+
+    assert!(regressions.is_empty(), "test ROMs failed: {}", regressions.join(", "));
+}
+
+fn find_roms(root: &Path, dir: &Path, suite: Option<Suite>, roms: &mut Vec<TestRom>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let file_name = entry.file_name().to_string_lossy().to_lowercase();
+        if path.is_dir() {
+            // The first blargg/mooneye directory on the way down decides the suite
+            let suite = suite.or(if file_name.starts_with("blargg") {
+                Some(Suite::Blargg)
+            } else if file_name.starts_with("mooneye") {
+                Some(Suite::Mooneye)
+            } else {
+                None
+            });
+            find_roms(root, &path, suite, roms);
+        } else if let Some(suite) = suite {
+            if file_name.ends_with(".gb") {
+                let name = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+                roms.push(TestRom { name, path, suite });
+            }
+        }
+    }
+}
+
+fn run_all(roms: &[TestRom]) -> Vec<Outcome> {
+    // Panics are part of the results, so keep the default hook from printing each one
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new(roms.iter().map(|_| None).collect());
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let rom = match roms.get(i) {
+                    Some(rom) => rom,
+                    None => break,
+                };
+                let outcome = run_rom(rom);
+                outcomes.lock().unwrap()[i] = Some(outcome);
+            });
+        }
+    });
+
+    panic::set_hook(default_hook);
+    outcomes.into_inner().unwrap().into_iter().map(|o| o.unwrap()).collect()
+}
+
+fn run_rom(rom: &TestRom) -> Outcome {
+    let data = match fs::read(&rom.path) {
+        Ok(data) => data,
+        Err(e) => return Outcome::Failed(format!("could not read ROM: {}", e)),
+    };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match rom.suite {
+        Suite::Blargg => run_blargg(&data),
+        Suite::Mooneye => run_mooneye(&data),
+    }));
+
+    result.unwrap_or_else(|payload| {
+        let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Outcome::Panicked(message)
+    })
+}
+
+fn run_blargg(rom: &[u8]) -> Outcome {
+    // Blargg tests report their result as text over the serial port
+    let serial = CaptureSerial::new();
+    let mut mb = match Motherboard::new(rom) {
+        Ok(mb) => mb,
+        Err(e) => return Outcome::Failed(format!("could not load ROM: {}", e)),
+    };
+    mb.set_serial_device(Box::new(serial.clone()));
+
+    for _ in 0..BLARGG_TIMEOUT_FRAMES {
+        if let Err(e) = mb.run_frame() {
+            return Outcome::Error(e.to_string());
+        }
+        mb.take_audio_samples();
+
+        let output = String::from_utf8_lossy(&serial.output()).into_owned();
+        if output.contains("Passed") {
+            return Outcome::Passed;
+        }
+        if output.contains("Failed") {
+            return Outcome::Failed(output.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+    }
+    Outcome::Timeout
+}
+
+fn run_mooneye(rom: &[u8]) -> Outcome {
+    let mut mb = match Motherboard::new(rom) {
+        Ok(mb) => mb,
+        Err(e) => return Outcome::Failed(format!("could not load ROM: {}", e)),
+    };
+
+    let mut cycles: u64 = 0;
+    while cycles < MOONEYE_TIMEOUT_FRAMES as u64 * rgbl::motherboard::MCYCLES_PER_FRAME as u64 {
+        if mb.mmu.read(mb.cpu.pc) == LD_B_B {
+            let r = &mb.cpu.regs;
+            let regs = [r.b, r.c, r.d, r.e, r.h, r.l];
+            return if regs == MOONEYE_PASS {
+                Outcome::Passed
+            } else {
+                Outcome::Failed(format!("B={} C={} D={} E={} H={} L={}", r.b, r.c, r.d, r.e, r.h, r.l))
+            };
+        }
+
+        cycles += match mb.tick() {
+            Ok(mcycles) => mcycles as u64,
+            Err(e) => return Outcome::Error(e.to_string()),
+        };
+    }
+    Outcome::Timeout
+}